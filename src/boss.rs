@@ -0,0 +1,455 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::collision_layers::CollisionMatrix;
+use crate::common::{EntityType, TextureWrapper};
+use crate::enemy::{spawn_enemy, SpawnEnemies, SpawnEnemy, ENEMY_BULLET_COLOR, ENEMY_BULLET_LAYER};
+use crate::enemy_archetype::EnemyArchetype;
+use crate::enemy_behaviour::{
+    default_projectile_range, BehaviourProfile, EnemyState, EnemyStateMachine, UpdateEnemyStates,
+};
+use crate::health_system::{DeathEvent, HealthData, ReadDeaths};
+use crate::hitbox::Hitbox;
+use crate::player::{spawn_bullet, BulletBundle};
+use crate::simulation::{run_in_state, SimulationAppExt, SIMULATION_TICK};
+use crate::AppState;
+
+/*
+    Bosses - enemies with several phases, read from JSON files in BOSSES_PATH.
+
+    A boss is an ordinary enemy (its archetype is a part of the file, so it moves
+    and behaves like any other enemy), additionally marked with BossMarker.
+    Its health decides the current phase: the boss is in the first phase whose
+    "health_above" is lower than the fraction of health left. Phases never go back.
+
+    In every phase the boss goes through the attack patterns of the phase one by one,
+    waiting "pattern_delay" seconds between them:
+        BulletRing : shoots projectiles in all directions (the range is optional),
+        Charge     : forces a charge (the archetype's behaviour has to define one),
+        Summon     : spawns enemies of the given archetype next to the boss.
+
+    Example of a file:
+        {
+            "id": "warden",
+            "name": "The Warden",
+            "archetype": { ...like in data/enemies... },
+            "phases": [
+                {
+                    "health_above": 0.5,
+                    "pattern_delay": 2.0,
+                    "patterns": [
+                        { "BulletRing": { "count": 12, "speed": 120.0, "damage": 2, "radius": 6.0 } },
+                        { "Charge": { "windup": 0.8 } }
+                    ]
+                },
+                {
+                    "health_above": 0.0,
+                    "pattern_delay": 1.5,
+                    "patterns": [ { "Summon": { "archetype": "runner", "count": 3 } } ]
+                }
+            ]
+        }
+
+    Send SpawnBoss to spawn a boss. While a boss is alive, its health bar is shown
+    at the top of the screen. BossDefeated is sent when a boss dies.
+*/
+
+const BOSSES_PATH: &str = "data/bosses";
+
+const HEALTH_BAR_COLOR: Color = Color::rgb(0.8, 0.1, 0.1);
+const HEALTH_BAR_BACKGROUND_COLOR: Color = Color::rgba(0.1, 0.1, 0.1, 0.8);
+
+pub struct BossPlugin;
+
+impl Plugin for BossPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SpawnBoss>()
+            .add_event::<BossDefeated>()
+            .add_startup_system(load_bosses)
+            .add_system_set(
+                SystemSet::on_update(AppState::InGame)
+                    .with_system(spawn_bosses)
+                    .with_system(show_boss_health_bar)
+                    .with_system(update_boss_health_bar),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::InGame).with_system(despawn_boss_health_bar),
+            )
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    .with_system(
+                        run_boss_patterns
+                            .after(UpdateEnemyStates)
+                            .before(SpawnEnemy),
+                    )
+                    .with_system(bosses_die.label(ReadDeaths)),
+            );
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AttackPattern {
+    BulletRing {
+        count: usize,
+        speed: f32,
+        damage: usize,
+        radius: f32,
+        #[serde(default = "default_projectile_range")]
+        range: f32,
+    },
+    Charge {
+        windup: f32,
+    },
+    Summon {
+        archetype: String,
+        count: usize,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BossPhase {
+    pub health_above: f32,
+    pub pattern_delay: f32,
+    pub patterns: Vec<AttackPattern>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BossDefinition {
+    pub id: String,
+    pub name: String,
+    pub archetype: EnemyArchetype,
+    pub phases: Vec<BossPhase>,
+}
+
+/// Definitions of bosses by their IDs.
+pub struct BossDefinitions {
+    bosses: HashMap<String, BossDefinition>,
+}
+
+impl BossDefinitions {
+    pub fn get(&self, id: &str) -> Option<&BossDefinition> {
+        self.bosses.get(id)
+    }
+}
+
+/// Message for asking to spawn the boss of a given ID.
+pub struct SpawnBoss {
+    pub id: String,
+    pub position: Vec2,
+}
+
+pub struct BossDefeated {
+    pub id: String,
+}
+
+#[derive(Component, Copy, Clone)]
+pub struct BossMarker;
+
+#[derive(Component)]
+pub struct BossController {
+    id: String,
+    name: String,
+    phases: Vec<BossPhase>,
+    phase: usize,
+    next_pattern: usize,
+    // Time left until the next pattern.
+    timer: f32,
+}
+
+impl BossController {
+    fn new(definition: &BossDefinition) -> BossController {
+        BossController {
+            id: definition.id.clone(),
+            name: definition.name.clone(),
+            phases: definition.phases.clone(),
+            phase: 0,
+            next_pattern: 0,
+            timer: definition
+                .phases
+                .first()
+                .map_or(0., |phase| phase.pattern_delay),
+        }
+    }
+
+    // Moves on to the phase matching the health, if it is a later one.
+    fn update_phase(&mut self, health_fraction: f32) {
+        let phase = self
+            .phases
+            .iter()
+            .position(|phase| health_fraction > phase.health_above)
+            .unwrap_or(self.phases.len().saturating_sub(1));
+        if phase > self.phase {
+            info!("{} enters phase {}.", self.name, phase + 1);
+            self.phase = phase;
+            self.next_pattern = 0;
+            self.timer = self.phases[phase].pattern_delay;
+        }
+    }
+}
+
+#[derive(Component)]
+struct BossHealthBar;
+
+#[derive(Component)]
+struct BossHealthBarFill;
+
+fn read_boss(path: &std::path::Path) -> Option<BossDefinition> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn load_bosses(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let mut bosses = HashMap::new();
+    let entries = fs::read_dir(BOSSES_PATH).expect("Couldn't read the bosses directory");
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if let Some(mut boss) = read_boss(&path) {
+            boss.archetype.texture_handle = asset_server.load(boss.archetype.texture.as_str());
+            bosses.insert(boss.id.clone(), boss);
+        } else {
+            eprintln!("[load_bosses] Incorrect boss file {}.", path.display());
+        }
+    }
+    commands.insert_resource(BossDefinitions { bosses });
+}
+
+fn spawn_bosses(
+    mut commands: Commands,
+    mut spawn_event: EventReader<SpawnBoss>,
+    definitions: Res<BossDefinitions>,
+    collision_matrix: Res<CollisionMatrix>,
+) {
+    for SpawnBoss { id, position } in spawn_event.iter() {
+        let definition = match definitions.get(id) {
+            Some(definition) => definition,
+            None => {
+                eprintln!("[spawn_bosses] There is no boss {}.", id);
+                continue;
+            }
+        };
+        spawn_boss(&mut commands, &collision_matrix, definition, *position);
+    }
+}
+
+pub fn spawn_boss(
+    commands: &mut Commands,
+    collision_matrix: &CollisionMatrix,
+    definition: &BossDefinition,
+    position: Vec2,
+) -> Entity {
+    let boss = spawn_enemy(
+        commands,
+        collision_matrix,
+        position.x,
+        position.y,
+        &definition.archetype,
+    );
+    commands
+        .entity(boss)
+        .insert(BossMarker)
+        .insert(BossController::new(definition));
+    boss
+}
+
+fn run_boss_patterns(
+    mut commands: Commands,
+    mut bosses: Query<
+        (
+            &Transform,
+            &HealthData,
+            &BehaviourProfile,
+            &mut EnemyStateMachine,
+            &mut BossController,
+        ),
+        With<BossMarker>,
+    >,
+    mut summon_writer: EventWriter<SpawnEnemies>,
+    textures: Res<Vec<TextureWrapper>>,
+    collision_matrix: Res<CollisionMatrix>,
+) {
+    for (transform, health, behaviour, mut machine, mut controller) in bosses.iter_mut() {
+        let health_fraction = health.current_health as f32 / health.max_health.max(1) as f32;
+        controller.update_phase(health_fraction);
+        controller.timer -= SIMULATION_TICK;
+        if controller.timer > 0. {
+            continue;
+        }
+        let phase = match controller.phases.get(controller.phase) {
+            Some(phase) if !phase.patterns.is_empty() => phase,
+            _ => continue,
+        };
+        let pattern = phase.patterns[controller.next_pattern % phase.patterns.len()].clone();
+        controller.timer = phase.pattern_delay;
+        controller.next_pattern += 1;
+
+        let position = transform.translation.truncate();
+        match pattern {
+            AttackPattern::BulletRing {
+                count,
+                speed,
+                damage,
+                radius,
+                range,
+            } => {
+                let texture = textures
+                    .iter()
+                    .find(|&x| x.owner_type == EntityType::Bullet)
+                    .map(|x| x.texture.clone())
+                    .unwrap_or_default();
+                let hitbox = Hitbox::new_circle(radius);
+                for i in 0..count {
+                    let angle = i as f32 * std::f32::consts::TAU / count as f32;
+                    spawn_bullet(
+                        &mut commands,
+                        BulletBundle::new(
+                            collision_matrix.layers(ENEMY_BULLET_LAYER),
+                            hitbox,
+                            Vec2::new(angle.cos(), angle.sin()),
+                            speed,
+                            damage,
+                            range,
+                        ),
+                        position,
+                        Sprite {
+                            color: ENEMY_BULLET_COLOR,
+                            custom_size: Some(hitbox.size()),
+                            ..Default::default()
+                        },
+                        texture.clone(),
+                    );
+                }
+            }
+            AttackPattern::Charge { windup } => {
+                if behaviour.charge.is_none() {
+                    eprintln!(
+                        "[run_boss_patterns] Boss {} cannot charge without a charge in its behaviour.",
+                        controller.id
+                    );
+                } else if machine.state == EnemyState::Chase {
+                    machine.set(EnemyState::ChargeWindup, windup);
+                }
+            }
+            AttackPattern::Summon { archetype, count } => {
+                summon_writer.send(SpawnEnemies {
+                    archetype,
+                    count,
+                    near: Some(position),
+                });
+            }
+        }
+    }
+}
+
+fn bosses_die(
+    mut death_reader: EventReader<DeathEvent>,
+    bosses: Query<&BossController, With<BossMarker>>,
+    mut defeated_writer: EventWriter<BossDefeated>,
+) {
+    for death in death_reader.iter() {
+        if let Ok(controller) = bosses.get(death.id) {
+            info!("{} has been defeated.", controller.name);
+            defeated_writer.send(BossDefeated {
+                id: controller.id.clone(),
+            });
+        }
+    }
+}
+
+fn show_boss_health_bar(
+    mut commands: Commands,
+    new_bosses: Query<&BossController, Added<BossMarker>>,
+    bars: Query<Entity, With<BossHealthBar>>,
+    asset_server: Res<AssetServer>,
+) {
+    let controller = match new_bosses.iter().next() {
+        Some(controller) => controller,
+        None => return,
+    };
+    for bar in bars.iter() {
+        commands.entity(bar).despawn_recursive();
+    }
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    left: Val::Percent(25.0),
+                    top: Val::Px(10.0),
+                    ..Default::default()
+                },
+                size: Size::new(Val::Percent(50.0), Val::Px(40.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(BossHealthBar)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    controller.name.clone(),
+                    TextStyle {
+                        font: asset_server.load("QuattrocentoSans-Bold.ttf"),
+                        font_size: 20.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(100.0), Val::Px(14.0)),
+                        ..Default::default()
+                    },
+                    color: HEALTH_BAR_BACKGROUND_COLOR.into(),
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(NodeBundle {
+                            style: Style {
+                                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                                ..Default::default()
+                            },
+                            color: HEALTH_BAR_COLOR.into(),
+                            ..Default::default()
+                        })
+                        .insert(BossHealthBarFill);
+                });
+        });
+}
+
+fn despawn_boss_health_bar(mut commands: Commands, bars: Query<Entity, With<BossHealthBar>>) {
+    for bar in bars.iter() {
+        commands.entity(bar).despawn_recursive();
+    }
+}
+
+fn update_boss_health_bar(
+    mut commands: Commands,
+    bosses: Query<&HealthData, With<BossMarker>>,
+    bars: Query<Entity, With<BossHealthBar>>,
+    mut fills: Query<&mut Style, With<BossHealthBarFill>>,
+) {
+    match bosses.iter().next() {
+        Some(health) => {
+            let fraction = health.current_health as f32 / health.max_health.max(1) as f32;
+            for mut style in fills.iter_mut() {
+                style.size.width = Val::Percent(100.0 * fraction);
+            }
+        }
+        // The boss is gone, and so is its health bar.
+        None => {
+            for bar in bars.iter() {
+                commands.entity(bar).despawn_recursive();
+            }
+        }
+    }
+}
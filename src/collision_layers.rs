@@ -0,0 +1,207 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/*
+    Collision layers decide how two objects of the move system interact.
+
+    Layers are defined by their names in COLLISION_LAYERS_PATH and get their bits
+    when the file is loaded into the CollisionMatrix resource, so a new kind
+    of objects only needs a new entry in the file. Every object of the move system
+    has a CollisionLayers component, built from the name of its layer
+    with CollisionMatrix::layers, holding its layer and two masks:
+        blocks  : layers of objects that stop the movement of this object,
+        reports : layers of objects that this object can overlap with,
+                  but collisions with them are still sent as CollisionEvents.
+
+    Two objects interact if any of them blocks or reports the other one.
+    Interacting objects always produce CollisionEvents; an object stops
+    only if its own blocks mask contains the layer of the other object.
+    To change how a single entity collides, modify its CollisionLayers.
+
+    Example of an entry of the file:
+        "PlayerBullet": { "blocks": ["Obstacle", "Enemy"], "reports": ["Pickup"] }
+*/
+
+const COLLISION_LAYERS_PATH: &str = "data/collision_layers.json";
+// Every layer is a bit of a LayerMask.
+const MAX_LAYERS: usize = LayerMask::BITS as usize;
+
+pub type LayerMask = u32;
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct CollisionLayers {
+    pub membership: LayerMask,
+    pub blocks: LayerMask,
+    pub reports: LayerMask,
+}
+
+impl CollisionLayers {
+    // Whether this object cannot move into the other one.
+    pub fn is_blocked_by(&self, other: &CollisionLayers) -> bool {
+        self.blocks & other.membership != 0
+    }
+
+    // Whether collisions of the two objects have any effect at all.
+    pub fn interacts_with(&self, other: &CollisionLayers) -> bool {
+        (self.blocks | self.reports) & other.membership != 0
+            || (other.blocks | other.reports) & self.membership != 0
+    }
+
+    // Whether the object belongs to any of the layers of the mask.
+    pub fn is_in(&self, mask: LayerMask) -> bool {
+        self.membership & mask != 0
+    }
+}
+
+// Entry of the file with collision layers.
+#[derive(Serialize, Deserialize)]
+struct LayerRule {
+    #[serde(default)]
+    blocks: Vec<String>,
+    #[serde(default)]
+    reports: Vec<String>,
+}
+
+pub struct CollisionMatrix {
+    // Bits of the layers by their names.
+    bits: HashMap<String, LayerMask>,
+    layers: HashMap<String, CollisionLayers>,
+    // Union of all masks, i.e. layers that anything can interact with.
+    used_layers: LayerMask,
+}
+
+impl CollisionMatrix {
+    pub fn load() -> CollisionMatrix {
+        let contents = fs::read_to_string(COLLISION_LAYERS_PATH)
+            .expect("Something went wrong reading the collision layers file");
+        let rules: HashMap<String, LayerRule> =
+            serde_json::from_str(&contents).expect("Incorrect collision layers file content");
+        CollisionMatrix::new(&rules)
+    }
+
+    fn new(rules: &HashMap<String, LayerRule>) -> CollisionMatrix {
+        if rules.len() > MAX_LAYERS {
+            panic!("There can be at most {} collision layers.", MAX_LAYERS);
+        }
+
+        // Sorted, so the bits do not depend on the order of the hashmap.
+        let mut names: Vec<&String> = rules.keys().collect();
+        names.sort();
+        let bits: HashMap<String, LayerMask> = names
+            .into_iter()
+            .enumerate()
+            .map(|(bit, name)| (name.clone(), 1 << bit))
+            .collect();
+        let mask_of = |layer: &str, names: &[String]| {
+            names.iter().fold(0, |mask, name| match bits.get(name) {
+                Some(bit) => mask | bit,
+                None => {
+                    eprintln!(
+                        "[CollisionMatrix::load] Layer {} refers to an unknown layer {}.",
+                        layer, name
+                    );
+                    mask
+                }
+            })
+        };
+
+        let layers: HashMap<String, CollisionLayers> = rules
+            .iter()
+            .map(|(name, rule)| {
+                let layers = CollisionLayers {
+                    membership: bits[name],
+                    blocks: mask_of(name, &rule.blocks),
+                    reports: mask_of(name, &rule.reports),
+                };
+                (name.clone(), layers)
+            })
+            .collect();
+        let used_layers = layers
+            .values()
+            .fold(0, |mask, layers| mask | layers.blocks | layers.reports);
+
+        CollisionMatrix {
+            bits,
+            layers,
+            used_layers,
+        }
+    }
+
+    // Bit of the layer of the given name, 0 if there is no such layer.
+    pub fn layer(&self, name: &str) -> LayerMask {
+        self.bits.get(name).copied().unwrap_or(0)
+    }
+
+    // Layers of objects of the given layer. Objects of unknown layers
+    // do not belong to any layer, so they do not interact with anything.
+    pub fn layers(&self, name: &str) -> CollisionLayers {
+        self.layers.get(name).copied().unwrap_or_default()
+    }
+
+    // Objects that nothing interacts with can be skipped by the move system.
+    pub fn is_inert(&self, layers: &CollisionLayers) -> bool {
+        layers.blocks == 0 && layers.reports == 0 && self.used_layers & layers.membership == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(json: &str) -> CollisionMatrix {
+        let rules: HashMap<String, LayerRule> = serde_json::from_str(json).unwrap();
+        CollisionMatrix::new(&rules)
+    }
+
+    #[test]
+    fn bits_follow_the_sorted_names() {
+        let matrix = matrix(r#"{ "Wall": {}, "Bullet": {}, "Hero": {} }"#);
+        assert_eq!(matrix.layer("Bullet"), 1);
+        assert_eq!(matrix.layer("Hero"), 2);
+        assert_eq!(matrix.layer("Wall"), 4);
+        assert_eq!(matrix.layers("Hero").membership, 2);
+    }
+
+    #[test]
+    fn unknown_layers_are_ignored() {
+        let matrix = matrix(r#"{ "Hero": { "blocks": ["Wall", "Ghost"] }, "Wall": {} }"#);
+        assert_eq!(matrix.layers("Hero").blocks, matrix.layer("Wall"));
+        assert_eq!(matrix.layer("Ghost"), 0);
+        assert_eq!(matrix.layers("Ghost"), CollisionLayers::default());
+    }
+
+    #[test]
+    fn only_layers_nothing_refers_to_are_inert() {
+        let matrix = matrix(r#"{ "Hero": { "reports": ["Coin"] }, "Coin": {}, "Decoration": {} }"#);
+        assert!(!matrix.is_inert(&matrix.layers("Hero")));
+        assert!(!matrix.is_inert(&matrix.layers("Coin")));
+        assert!(matrix.is_inert(&matrix.layers("Decoration")));
+    }
+
+    #[test]
+    fn blocking_is_one_sided_but_interaction_is_symmetric() {
+        let matrix = matrix(
+            r#"{
+                "Hero": { "blocks": ["Wall"], "reports": ["Coin"] },
+                "Wall": {},
+                "Coin": {},
+                "Decoration": {}
+            }"#,
+        );
+        let hero = matrix.layers("Hero");
+        let wall = matrix.layers("Wall");
+        let coin = matrix.layers("Coin");
+        let decoration = matrix.layers("Decoration");
+
+        assert!(hero.is_blocked_by(&wall));
+        assert!(!wall.is_blocked_by(&hero));
+        assert!(!hero.is_blocked_by(&coin));
+        assert!(hero.interacts_with(&wall) && wall.interacts_with(&hero));
+        assert!(hero.interacts_with(&coin) && coin.interacts_with(&hero));
+        assert!(!hero.interacts_with(&decoration) && !decoration.interacts_with(&hero));
+        assert!(!wall.interacts_with(&coin));
+        assert!(coin.is_in(matrix.layer("Coin") | matrix.layer("Wall")));
+    }
+}
@@ -0,0 +1,222 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+
+use crate::collision_layers::{CollisionLayers, CollisionMatrix, LayerMask};
+use crate::hitbox::Hitbox;
+use crate::move_system::MoveSystemMarker;
+use crate::simulation::SimulationAppExt;
+use crate::spatial_hash::SpatialHash;
+
+/*
+    Queries about the objects of the move system, without moving anything.
+
+    Add a CollisionWorld parameter to a system to cast rays (e.g. line of sight,
+    lasers) or whole hitboxes (e.g. checking if an enemy fits somewhere)
+    against all entities with MoveSystemMarker. The results can be limited
+    with a CastFilter (e.g. to the layers from CollisionWorld::layer)
+    and are sorted by distance.
+
+    CollisionWorld reads Transforms, so it cannot be used together
+    with queries mutating Transforms in the same system.
+
+    To avoid testing every object (e.g. every map tile) for every cast, the objects
+    are put into a SpatialHash (CollisionIndex) at the beginning of every simulation tick
+    and a cast only tests the objects in the cells along its path. Systems casting
+    should run after systems labeled IndexCollisionObjects and before objects are moved,
+    as the index knows where the objects were at the beginning of the tick.
+*/
+
+// Size of a cell of the index, a bit bigger than the map tiles.
+const INDEX_CELL_SIZE: f32 = 64.;
+
+pub struct CollisionWorldPlugin;
+
+impl Plugin for CollisionWorldPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CollisionIndex::new())
+            .add_simulation_system(index_collision_objects.label(IndexCollisionObjects));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct IndexCollisionObjects;
+
+/// Which objects should be taken into account by a cast.
+#[derive(Clone, Default)]
+pub struct CastFilter {
+    /// If set, only objects belonging to one of these layers can be hit.
+    pub layers: Option<LayerMask>,
+    /// Objects that are never hit, e.g. the one casting.
+    pub excluded: Vec<Entity>,
+}
+
+impl CastFilter {
+    pub fn new() -> CastFilter {
+        CastFilter::default()
+    }
+
+    pub fn with_layers(mut self, layers: LayerMask) -> CastFilter {
+        self.layers = Some(layers);
+        self
+    }
+
+    pub fn excluding(mut self, entity: Entity) -> CastFilter {
+        self.excluded.push(entity);
+        self
+    }
+
+    fn accepts(&self, entity: Entity, layers: &CollisionLayers) -> bool {
+        !self.excluded.contains(&entity) && self.layers.is_none_or(|mask| layers.is_in(mask))
+    }
+}
+
+pub struct CastHit {
+    pub entity: Entity,
+    pub layers: CollisionLayers,
+    pub distance: f32,
+    // Position of the ray (or of the center of the cast shape) at the moment of the hit.
+    pub point: Vec2,
+}
+
+type CollisionObjectItem = (
+    Entity,
+    &'static Transform,
+    &'static Hitbox,
+    &'static CollisionLayers,
+);
+
+/// Objects of the move system put into a SpatialHash by their bounding boxes.
+pub struct CollisionIndex {
+    grid: SpatialHash,
+    entities: Vec<Entity>,
+}
+
+impl CollisionIndex {
+    pub fn new() -> CollisionIndex {
+        CollisionIndex {
+            grid: SpatialHash::new(INDEX_CELL_SIZE),
+            entities: vec![],
+        }
+    }
+}
+
+fn index_collision_objects(
+    mut index: ResMut<CollisionIndex>,
+    objects: Query<(Entity, &Transform, &Hitbox), With<MoveSystemMarker>>,
+) {
+    *index = CollisionIndex::new();
+    for (entity, transform, hitbox) in objects.iter() {
+        let position = transform.translation.truncate();
+        let half_extents = hitbox.half_extents();
+        let i = index.entities.len();
+        index
+            .grid
+            .insert(i, position - half_extents, position + half_extents);
+        index.entities.push(entity);
+    }
+}
+
+#[derive(SystemParam)]
+pub struct CollisionWorld<'w, 's> {
+    objects: Query<'w, 's, CollisionObjectItem, With<MoveSystemMarker>>,
+    index: Res<'w, CollisionIndex>,
+    collision_matrix: Res<'w, CollisionMatrix>,
+}
+
+impl<'w, 's> CollisionWorld<'w, 's> {
+    // Bit of the collision layer of the given name, e.g. to use in a CastFilter.
+    pub fn layer(&self, name: &str) -> LayerMask {
+        self.collision_matrix.layer(name)
+    }
+
+    // All objects hit by the ray, the closest first.
+    pub fn raycast(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Vec<CastHit> {
+        self.cast(
+            origin,
+            direction,
+            max_distance,
+            Vec2::ZERO,
+            filter,
+            |hitbox, position, direction| hitbox.raycast(position, origin, direction, max_distance),
+        )
+    }
+
+    // All objects the shape would overlap when moved along the ray, the closest first.
+    pub fn shapecast(
+        &self,
+        shape: &Hitbox,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Vec<CastHit> {
+        self.cast(
+            origin,
+            direction,
+            max_distance,
+            shape.half_extents(),
+            filter,
+            |hitbox, position, direction| {
+                Hitbox::shapecast(shape, origin, direction, max_distance, hitbox, position)
+            },
+        )
+    }
+
+    // Whether nothing accepted by the filter lies on the segment between the two points.
+    pub fn line_of_sight(&self, from: Vec2, to: Vec2, filter: &CastFilter) -> bool {
+        let offset = to - from;
+        let distance = offset.length();
+        if distance == 0. {
+            return true;
+        }
+        self.raycast(from, offset / distance, distance, filter)
+            .is_empty()
+    }
+
+    // Tests the objects within the reach (half extents of the cast shape)
+    // of the segment from the origin, with the distance_to function.
+    fn cast(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        reach: Vec2,
+        filter: &CastFilter,
+        distance_to: impl Fn(&Hitbox, Vec2, Vec2) -> Option<f32>,
+    ) -> Vec<CastHit> {
+        let direction = direction.normalize_or_zero();
+        let (min, max) = match self.index.grid.bounds() {
+            Some(bounds) if direction != Vec2::ZERO => bounds,
+            _ => return vec![],
+        };
+        // There is nothing to hit further than the farthest corner of the index,
+        // so rays can be endless.
+        let farthest = (origin - min).abs().max((origin - max).abs()).length() + reach.length();
+        let end = origin + direction * max_distance.min(farthest);
+        let mut hits: Vec<CastHit> = self
+            .index
+            .grid
+            .query_segment(origin, end, reach)
+            .into_iter()
+            .filter_map(|i| self.objects.get(self.index.entities[i]).ok())
+            .filter(|(entity, _, _, layers)| filter.accepts(*entity, layers))
+            .filter_map(|(entity, transform, hitbox, &layers)| {
+                let position = transform.translation.truncate();
+                distance_to(hitbox, position, direction).map(|distance| CastHit {
+                    entity,
+                    layers,
+                    distance,
+                    point: origin + direction * distance,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+}
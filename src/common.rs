@@ -17,7 +17,7 @@ pub enum EntityType {
     Wall,
     Floor,
     Player,
-    Enemy,
+    Bullet,
 }
 
 struct TextureInfo {
@@ -34,14 +34,14 @@ const TEXTURES: &[TextureInfo] = &[
         path: "floor.png",
         owner_type: EntityType::Floor,
     },
-    TextureInfo {
-        path: "enemy.png",
-        owner_type: EntityType::Enemy,
-    },
     TextureInfo {
         path: "player.png",
         owner_type: EntityType::Player,
     },
+    TextureInfo {
+        path: "tear.png",
+        owner_type: EntityType::Bullet,
+    },
 ];
 
 pub struct TextureWrapper {
@@ -65,3 +65,12 @@ pub fn load_textures(mut commands: Commands, asset_server: Res<AssetServer>) {
 
     commands.insert_resource(textures);
 }
+
+/// Rotates the vector counterclockwise by the angle (in radians).
+pub fn rotate(vector: Vec2, angle: f32) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(
+        vector.x * cos - vector.y * sin,
+        vector.x * sin + vector.y * cos,
+    )
+}
@@ -1,44 +1,104 @@
 use bevy::ecs::event::Events;
 use bevy::prelude::*;
 
+use crate::collision_layers::CollisionMatrix;
+use crate::collision_world::IndexCollisionObjects;
 use crate::common::{EntityType, TextureWrapper};
 use crate::move_system::{
     CollisionEvent, ModifyVelocity, MoveSystemObjectWithVelocity, VelocityVector,
 };
-use crate::player::{BulletMarker, PlayerMarker, Speed};
+use crate::player::{
+    spawn_bullet, BulletBundle, BulletMarker, PlayerMarker, Speed, PLAYER_BULLET_LAYER,
+};
 
-use crate::health_system::{DeathEvent, HealthData, ModifyHealth, ReadDeaths, TakeDamageEvent};
+use crate::enemy_archetype::{load_enemy_archetypes, EnemyArchetype, EnemyArchetypes};
+use crate::enemy_behaviour::{
+    update_enemy_states, BehaviourProfile, EnemyAttack, EnemyNavigation, EnemyState,
+    EnemyStateMachine, UpdateEnemyStates,
+};
+use crate::health_system::{
+    ContactDamage, Damage, DamageCooldowns, DeathEvent, HealthData, ModifyHealth, ReadDeaths,
+    TakeDamageEvent,
+};
 use crate::hitbox::Hitbox;
-use crate::move_system::MoveObjectType;
-use crate::{hitbox, player, AppState, Player};
+use crate::navigation::{FlowField, GridCell, NavGrid, UpdateFlowField, NAV_CELL_SIZE};
+use crate::projectile::Piercing;
+use crate::simulation::{run_in_state, GameRng, SimulationAppExt};
+use crate::spawn_placement::SpawnPlacement;
+use crate::steering::{
+    self, Neighbour, Neighbourhood, AVOIDANCE_WEIGHT, COHESION_WEIGHT, SEPARATION_WEIGHT,
+};
+use crate::{hitbox, player, AppState};
 
 use rand::Rng;
 
-const ENEMY_START_SPEED: f32 = 20.0;
-const ENEMY_SIZE: f32 = 20.0;
+// Collision layers of enemies and their bullets.
+pub const ENEMY_LAYER: &str = "Enemy";
+pub const ENEMY_BULLET_LAYER: &str = "EnemyBullet";
+// How close to a waypoint an enemy has to get to head for the next one.
+const WAYPOINT_REACHED_DISTANCE: f32 = NAV_CELL_SIZE / 4.;
+// Wandering enemies are not in a hurry.
+const WANDER_SPEED_FACTOR: f32 = 0.5;
+pub const ENEMY_BULLET_COLOR: Color = Color::rgb(0.9, 0.3, 0.2);
+// How often the same source (e.g. a piercing bullet) can hurt an enemy.
+const ENEMY_DAMAGE_COOLDOWN: f32 = 0.5;
+// How far from the given position enemies spawned near it can appear.
+const SPAWN_NEAR_DISTANCE: f32 = 60.;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
 pub struct SpawnEnemy;
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+struct FindPaths;
+
 pub struct EnemyPlugin;
 
 impl Plugin for EnemyPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Events::<SpawnEnemies>::default())
-            .add_system_set(
-                SystemSet::on_update(AppState::InGame)
+            .add_event::<EnemyAttack>()
+            .add_startup_system(load_enemy_archetypes)
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_all_enemies))
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    // In the same tick as they are asked for, e.g. by a boss,
+                    // so a room is never cleared while they are on their way.
                     .with_system(spawn_enemies.label(SpawnEnemy))
-                    .with_system(move_enemies.label(ModifyVelocity))
+                    .with_system(
+                        update_enemy_states
+                            .label(UpdateEnemyStates)
+                            .after(IndexCollisionObjects),
+                    )
+                    .with_system(find_enemy_paths.label(FindPaths).after(UpdateEnemyStates))
+                    .with_system(
+                        move_enemies
+                            .label(ModifyVelocity)
+                            .after(UpdateEnemyStates)
+                            .after(FindPaths)
+                            .after(UpdateFlowField),
+                    )
+                    .with_system(enemies_shoot.after(UpdateEnemyStates))
                     .with_system(enemies_take_damage.label(ModifyHealth))
                     .with_system(despawn_dead_enemies.label(ReadDeaths)),
-            )
-            .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(setup_enemies));
+            );
     }
 }
 
 #[derive(Component, Copy, Clone)]
 pub struct EnemyMarker;
 
+// Path of an enemy towards the player. It is recomputed only when
+// the player moves to another cell or the navigation grid changes.
+// Enemies without it follow the flow field shared by all enemies,
+// which is a lot cheaper, but the paths are less smooth.
+#[derive(Component, Default)]
+pub struct EnemyPath {
+    waypoints: Vec<Vec2>,
+    goal: Option<GridCell>,
+    grid_generation: u32,
+}
+
 #[derive(Bundle)]
 pub struct EnemyBundle {
     marker: EnemyMarker,
@@ -46,118 +106,291 @@ pub struct EnemyBundle {
     #[bundle]
     move_system_bundle: MoveSystemObjectWithVelocity,
     health_data: HealthData,
+    damage_cooldowns: DamageCooldowns,
+    contact_damage: ContactDamage,
+    behaviour: BehaviourProfile,
+    state_machine: EnemyStateMachine,
 }
 
 impl EnemyBundle {
-    pub fn new(hitbox: Hitbox, max_health: usize) -> EnemyBundle {
+    pub fn new(archetype: &EnemyArchetype, collision_matrix: &CollisionMatrix) -> EnemyBundle {
         EnemyBundle {
             marker: EnemyMarker,
-            speed: Speed(ENEMY_START_SPEED),
+            speed: Speed(archetype.speed),
             move_system_bundle: MoveSystemObjectWithVelocity::new_with_vel_0(
-                MoveObjectType::Enemy,
-                hitbox,
+                collision_matrix.layers(ENEMY_LAYER),
+                archetype.hitbox,
             ),
-            health_data: HealthData::new_healthy(max_health),
+            health_data: HealthData::new_healthy(archetype.health),
+            damage_cooldowns: DamageCooldowns::new(ENEMY_DAMAGE_COOLDOWN),
+            contact_damage: ContactDamage(archetype.contact_damage),
+            behaviour: archetype.behaviour,
+            state_machine: EnemyStateMachine::new(),
         }
     }
 }
 
-fn spawn_enemy(commands: &mut Commands, x: f32, y: f32, texture: Handle<Image>) {
-    commands
-        .spawn_bundle(EnemyBundle::new(
-            hitbox::Hitbox::new_rectangle(Vec2::new(ENEMY_SIZE, ENEMY_SIZE)),
-            20,
-        ))
-        .insert_bundle(SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(ENEMY_SIZE, ENEMY_SIZE)),
-                ..Default::default()
-            },
-            transform: Transform {
-                translation: Vec3::new(x, y, 1.0),
-                ..Default::default()
-            },
-            texture,
+pub fn spawn_enemy(
+    commands: &mut Commands,
+    collision_matrix: &CollisionMatrix,
+    x: f32,
+    y: f32,
+    archetype: &EnemyArchetype,
+) -> Entity {
+    let mut enemy = commands.spawn_bundle(EnemyBundle::new(archetype, collision_matrix));
+    enemy.insert_bundle(SpriteBundle {
+        sprite: Sprite {
+            custom_size: Some(archetype.hitbox.size()),
+            ..Default::default()
+        },
+        transform: Transform {
+            translation: Vec3::new(x, y, 1.0),
             ..Default::default()
-        });
+        },
+        texture: archetype.texture_handle.clone(),
+        ..Default::default()
+    });
+    if archetype.behaviour.navigation == EnemyNavigation::Path {
+        enemy.insert(EnemyPath::default());
+    }
+    enemy.id()
 }
 
-pub struct SpawnEnemies(pub usize);
+/// Message for asking to spawn enemies of the archetype of a given ID.
+/// Without a position, they are spawned anywhere, but not too close to the player.
+pub struct SpawnEnemies {
+    pub archetype: String,
+    pub count: usize,
+    pub near: Option<Vec2>,
+}
 
 fn spawn_enemies(
     mut commands: Commands,
     mut spawn_event: EventReader<SpawnEnemies>,
-    player: Query<(&Transform), (With<PlayerMarker>)>,
-    textures: Res<Vec<TextureWrapper>>,
+    placement: SpawnPlacement,
+    archetypes: Res<EnemyArchetypes>,
+    collision_matrix: Res<CollisionMatrix>,
+    mut rng: ResMut<GameRng>,
 ) {
-    let get_random_position = || {
-        (
-            rand::thread_rng().gen_range(-400.0..400.0) as f32,
-            rand::thread_rng().gen_range(-220.0..220.0) as f32,
-        )
-    };
-
-    let get_position = |x: f32, y: f32| loop {
-        let (x_, y_) = get_random_position();
-        let (x_diff, y_diff) = (x - x_, y - y_);
-        if x_diff.abs() + y_diff.abs() > 100.0 {
-            return (x_, y_);
-        }
-    };
+    // Enemies spawned in the same tick should not overlap.
+    let mut taken = vec![];
+    for SpawnEnemies {
+        archetype,
+        count,
+        near,
+    } in spawn_event.iter()
+    {
+        let archetype = match archetypes.get(archetype) {
+            Some(archetype) => archetype,
+            None => {
+                eprintln!("[spawn_enemies] There is no enemy archetype {}.", archetype);
+                continue;
+            }
+        };
 
-    if let Some(enemy_texture) = textures.iter().find(|&x| x.owner_type == EntityType::Enemy) {
-        let mut spawn_one = |position: (f32, f32)| {
+        for _ in 0..*count {
+            let position = match near {
+                Some(point) => placement.near(
+                    *point,
+                    SPAWN_NEAR_DISTANCE,
+                    &archetype.hitbox,
+                    &mut taken,
+                    &mut *rng,
+                ),
+                None => placement.anywhere(&archetype.hitbox, &mut taken, &mut *rng),
+            };
+            let position = match position {
+                Some(position) => position,
+                None => {
+                    eprintln!("[spawn_enemies] There is no place for {}.", archetype.id);
+                    continue;
+                }
+            };
             spawn_enemy(
                 &mut commands,
-                position.0,
-                position.1,
-                enemy_texture.texture.clone(),
+                &collision_matrix,
+                position.x,
+                position.y,
+                archetype,
             );
-        };
-
-        let enemies_count = spawn_event.iter().map(|SpawnEnemies(count)| count).sum();
+        }
+    }
+}
 
-        if let Ok(player_transform) = player.get_single() {
-            let (player_x, player_y) = (
-                player_transform.translation.x,
-                player_transform.translation.y,
-            );
-            for _ in 0..enemies_count {
-                spawn_one(get_position(player_x, player_y));
+fn find_enemy_paths(
+    mut enemies: Query<(&Transform, &mut EnemyPath, &EnemyStateMachine), (With<EnemyMarker>)>,
+    player: Query<(&Transform), (With<PlayerMarker>)>,
+    nav_grid: Res<NavGrid>,
+) {
+    if let Ok(player_transform) = player.get_single() {
+        let player_position = player_transform.translation.truncate();
+        let player_cell = nav_grid.cell_of(player_position);
+        for (transform, mut path, machine) in enemies.iter_mut() {
+            // Paths are only needed while chasing.
+            if machine.state != EnemyState::Chase {
+                continue;
             }
-        } else {
-            for _ in 0..enemies_count {
-                spawn_one(get_random_position());
+            let enemy_position = transform.translation.truncate();
+            if path.goal != player_cell || path.grid_generation != nav_grid.generation() {
+                path.waypoints = nav_grid
+                    .find_path(enemy_position, player_position)
+                    .unwrap_or_default();
+                path.goal = player_cell;
+                path.grid_generation = nav_grid.generation();
+            } else if let Some(last) = path.waypoints.last_mut() {
+                // The player moved, but stayed in the same cell.
+                *last = player_position;
+            }
+
+            while path.waypoints.len() > 1
+                && path.waypoints[0].distance(enemy_position) < WAYPOINT_REACHED_DISTANCE
+            {
+                path.waypoints.remove(0);
             }
         }
-    } else {
-        panic!()
     }
 }
 
+type MovingEnemyItem<'a> = (
+    Entity,
+    &'a Transform,
+    &'a Hitbox,
+    &'a mut VelocityVector,
+    &'a Speed,
+    &'a BehaviourProfile,
+    &'a EnemyStateMachine,
+    Option<&'a EnemyPath>,
+);
+
 fn move_enemies(
-    mut enemies: Query<(&Transform, &mut VelocityVector, &Speed), (With<EnemyMarker>)>,
+    mut enemies: Query<MovingEnemyItem, (With<EnemyMarker>)>,
     player: Query<(&Transform), (With<PlayerMarker>)>,
+    nav_grid: Res<NavGrid>,
+    flow_field: Res<FlowField>,
 ) {
     if let Ok(player_transform) = player.get_single() {
-        let player_position = (
-            player_transform.translation.x,
-            player_transform.translation.y,
+        let player_position = player_transform.translation.truncate();
+        let neighbourhood = Neighbourhood::new(
+            enemies
+                .iter()
+                .map(|(entity, transform, hitbox, ..)| Neighbour {
+                    entity,
+                    position: transform.translation.truncate(),
+                    radius: hitbox.half_extents().max_element(),
+                })
+                .collect(),
         );
-        for (position, mut vel, &Speed(speed)) in enemies.iter_mut() {
-            let enemy_position = (position.translation.x, position.translation.y);
-            let translation = Vec2::new(
-                player_position.0 - enemy_position.0,
-                player_position.1 - enemy_position.1,
-            )
-            .normalize_or_zero();
-            vel.0 += translation * speed;
+
+        for (entity, position, hitbox, mut vel, &Speed(speed), behaviour, machine, path) in
+            enemies.iter_mut()
+        {
+            let enemy_position = position.translation.truncate();
+            let distance = enemy_position.distance(player_position);
+            let straight = (player_position - enemy_position).normalize_or_zero();
+            let translation = match machine.state {
+                EnemyState::Chase if distance < behaviour.preferred_distance => -straight * speed,
+                EnemyState::Chase => {
+                    // Without a path (e.g. no map is rendered) go straight to the player.
+                    let direction = match path {
+                        Some(path) => path.waypoints.first().map_or(straight, |&waypoint| {
+                            (waypoint - enemy_position).normalize_or_zero()
+                        }),
+                        None => flow_field
+                            .direction_at(&nav_grid, enemy_position)
+                            .unwrap_or(straight),
+                    };
+                    let stopping_distance = behaviour
+                        .preferred_distance
+                        .max(hitbox.half_extents().max_element());
+                    direction * speed * steering::arrival(distance, stopping_distance)
+                }
+                EnemyState::Flee => -straight * speed,
+                EnemyState::Wander { direction } => direction * speed * WANDER_SPEED_FACTOR,
+                // Charging enemies do not steer, they rush straight ahead.
+                EnemyState::Charge { direction } => {
+                    vel.0 += direction * behaviour.charge.map_or(speed, |charge| charge.speed);
+                    continue;
+                }
+                EnemyState::Idle | EnemyState::ChargeWindup | EnemyState::AttackWindup => {
+                    Vec2::ZERO
+                }
+            };
+
+            let me = Neighbour {
+                entity,
+                position: enemy_position,
+                radius: hitbox.half_extents().max_element(),
+            };
+            let mut steering = neighbourhood.separation(&me) * SEPARATION_WEIGHT;
+            if matches!(machine.state, EnemyState::Chase | EnemyState::Wander { .. }) {
+                steering += neighbourhood.cohesion(&me) * COHESION_WEIGHT;
+            }
+            steering += steering::avoid_obstacles(&nav_grid, enemy_position, translation)
+                * AVOIDANCE_WEIGHT;
+            vel.0 += (translation + steering * speed).clamp_length_max(speed);
         }
     }
 }
 
-fn setup_enemies(mut enemies: EventWriter<SpawnEnemies>) {
-    enemies.send(SpawnEnemies(3));
+// Enemies with a projectile fire it at the player when they finish winding up an attack.
+fn enemies_shoot(
+    mut commands: Commands,
+    mut attack_reader: EventReader<EnemyAttack>,
+    enemies: Query<(&Transform, &BehaviourProfile), (With<EnemyMarker>)>,
+    player: Query<(&Transform), (With<PlayerMarker>)>,
+    textures: Res<Vec<TextureWrapper>>,
+    collision_matrix: Res<CollisionMatrix>,
+) {
+    let player_position = match player.get_single() {
+        Ok(transform) => transform.translation.truncate(),
+        Err(_) => return,
+    };
+    let texture = textures
+        .iter()
+        .find(|&x| x.owner_type == EntityType::Bullet)
+        .map(|x| x.texture.clone())
+        .unwrap_or_default();
+
+    for EnemyAttack { enemy } in attack_reader.iter() {
+        let (transform, behaviour) = match enemies.get(*enemy) {
+            Ok(enemy) => enemy,
+            Err(_) => continue,
+        };
+        let projectile = match behaviour.attack.and_then(|attack| attack.projectile) {
+            Some(projectile) => projectile,
+            None => continue,
+        };
+        let enemy_position = transform.translation.truncate();
+        let aim = (player_position - enemy_position).normalize_or_zero();
+        if aim == Vec2::ZERO {
+            continue;
+        }
+        let hitbox = Hitbox::new_circle(projectile.radius);
+        for i in 0..projectile.count {
+            // Offsets from the aimed direction, e.g. -1, 0, 1 for three projectiles.
+            let offset = i as f32 - (projectile.count - 1) as f32 / 2.;
+            let angle = aim.y.atan2(aim.x) + offset * projectile.spread.to_radians();
+            let direction = Vec2::new(angle.cos(), angle.sin());
+            spawn_bullet(
+                &mut commands,
+                BulletBundle::new(
+                    collision_matrix.layers(ENEMY_BULLET_LAYER),
+                    hitbox,
+                    direction,
+                    projectile.speed,
+                    projectile.damage,
+                    projectile.range,
+                ),
+                enemy_position,
+                Sprite {
+                    color: ENEMY_BULLET_COLOR,
+                    custom_size: Some(hitbox.size()),
+                    ..Default::default()
+                },
+                texture.clone(),
+            );
+        }
+    }
 }
 
 fn despawn_dead_enemies(
@@ -172,21 +405,36 @@ fn despawn_dead_enemies(
     }
 }
 
+fn despawn_all_enemies(mut commands: Commands, enemies: Query<Entity, With<EnemyMarker>>) {
+    for enemy in enemies.iter() {
+        commands.entity(enemy).despawn();
+    }
+}
+
 fn enemies_take_damage(
     mut collision_reade: EventReader<CollisionEvent>,
     mut damage_writer: EventWriter<TakeDamageEvent>,
     mut enemies: Query<(Entity), (With<EnemyMarker>)>,
-    mut bullets: Query<(Entity), (With<BulletMarker>)>,
+    mut bullets: Query<(&Damage, Option<&Piercing>), (With<BulletMarker>)>,
+    collision_matrix: Res<CollisionMatrix>,
 ) {
-    for collision in collision_reade.iter().filter(|col| {
-        col.object_type == MoveObjectType::Enemy
-            && col.collided_with_type == MoveObjectType::PlayerBullet
-    }) {
+    let player_bullet_layer = collision_matrix.layer(PLAYER_BULLET_LAYER);
+    for collision in collision_reade
+        .iter()
+        .filter(|col| col.collided_with_layers.is_in(player_bullet_layer))
+    {
         // info!("collision");
-        if let Ok(enemie) = enemies.get(collision.object_id) {
+        if let (Ok(enemie), Ok((&Damage(damage), piercing))) = (
+            enemies.get(collision.object_id),
+            bullets.get(collision.collided_with_id),
+        ) {
+            if piercing.is_some_and(|piercing| piercing.spares(enemie)) {
+                continue;
+            }
             damage_writer.send(TakeDamageEvent {
                 id: enemie,
-                amount: 1,
+                amount: damage,
+                source: Some(collision.collided_with_id),
             });
         }
     }
@@ -0,0 +1,92 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::enemy_behaviour::BehaviourProfile;
+use crate::hitbox::Hitbox;
+
+/*
+    Kinds of enemies, read from JSON files in ENEMY_ARCHETYPES_PATH
+    (one archetype per file), so new enemies can be added without recompiling.
+
+    Example of a file:
+        {
+            "id": "grunt",
+            "speed": 20.0,
+            "health": 20,
+            "hitbox": { "Circle": 10.0 },
+            "texture": "enemy.png",
+            "contact_damage": 1,
+            "cost": 1,
+            "behaviour": {
+                "navigation": "FlowField",
+                "sight_range": 400.0,
+                "wander": true,
+                "flee_below_health": 0.25,
+                "charge": { "range": 150.0, "windup": 0.5, "speed": 120.0, "duration": 0.8, "cooldown": 3.0 }
+            }
+        }
+
+    The texture path is relative to the assets directory.
+    The cost is paid from the budget of a wave to spawn the enemy (1 by default).
+    Everything in "behaviour" except "navigation" is optional
+    (description of the fields in file enemy_behaviour.rs).
+*/
+
+const ENEMY_ARCHETYPES_PATH: &str = "data/enemies";
+
+fn default_cost() -> usize {
+    1
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EnemyArchetype {
+    pub id: String,
+    pub speed: f32,
+    pub health: usize,
+    pub hitbox: Hitbox,
+    pub texture: String,
+    pub contact_damage: usize,
+    #[serde(default = "default_cost")]
+    pub cost: usize,
+    pub behaviour: BehaviourProfile,
+    // Filled in after loading the file.
+    #[serde(skip)]
+    pub texture_handle: Handle<Image>,
+}
+
+/// Archetypes of enemies by their IDs.
+pub struct EnemyArchetypes {
+    archetypes: HashMap<String, EnemyArchetype>,
+}
+
+impl EnemyArchetypes {
+    pub fn get(&self, id: &str) -> Option<&EnemyArchetype> {
+        self.archetypes.get(id)
+    }
+}
+
+fn read_archetype(path: &std::path::Path) -> Option<EnemyArchetype> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+pub fn load_enemy_archetypes(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let mut archetypes = HashMap::new();
+    let entries =
+        fs::read_dir(ENEMY_ARCHETYPES_PATH).expect("Couldn't read the enemy archetypes directory");
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if let Some(mut archetype) = read_archetype(&path) {
+            archetype.texture_handle = asset_server.load(archetype.texture.as_str());
+            archetypes.insert(archetype.id.clone(), archetype);
+        } else {
+            eprintln!(
+                "[load_enemy_archetypes] Incorrect enemy archetype file {}.",
+                path.display()
+            );
+        }
+    }
+    commands.insert_resource(EnemyArchetypes { archetypes });
+}
@@ -0,0 +1,285 @@
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::collision_world::{CastFilter, CollisionWorld};
+use crate::enemy::EnemyMarker;
+use crate::health_system::HealthData;
+use crate::map::OBSTACLE_LAYER;
+use crate::player::PlayerMarker;
+use crate::simulation::{GameRng, SIMULATION_TICK};
+
+/*
+    Behaviour of enemies as a state machine.
+
+    Every enemy has an EnemyStateMachine component holding its current EnemyState.
+    Which states an enemy can get into and when, is described by its BehaviourProfile
+    (part of the enemy archetype), so kinds of enemies can mix behaviours freely:
+
+        Idle         : stands still, when it cannot see the player and does not wander,
+        Wander       : walks around randomly, when it cannot see the player,
+        Chase        : follows the player (with a path or the flow field),
+        Flee         : runs away from the player when its health is low,
+        ChargeWindup : stands still for a moment before charging,
+        Charge       : rushes in a straight line at a high speed,
+        AttackWindup : stands still for a moment, then sends an EnemyAttack event
+                       (enemies with a projectile shoot at the player then).
+
+    The enemy sees the player when the player is within its sight range and no
+    obstacle is in the way. After losing sight of the player, the enemy keeps
+    chasing it for some time. Enemies with a preferred distance (e.g. shooting ones)
+    back off while chasing, when the player is closer than that.
+
+    Windups and charges cannot be interrupted (except for fleeing).
+    update_enemy_states only decides about the state; move_enemies
+    (in file enemy.rs) moves enemies accordingly, so it has to run
+    after systems labeled UpdateEnemyStates.
+*/
+
+// How often wandering enemies change direction.
+const WANDER_INTERVAL: f32 = 2.;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct UpdateEnemyStates;
+
+fn default_sight_range() -> f32 {
+    f32::INFINITY
+}
+
+fn default_memory() -> f32 {
+    3.
+}
+
+fn default_projectile_count() -> usize {
+    1
+}
+
+pub fn default_projectile_range() -> f32 {
+    600.
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnemyNavigation {
+    // The flow field shared by all enemies - cheap, good for crowds.
+    FlowField,
+    // An own A* path - smoother, but more expensive.
+    Path,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ChargeProfile {
+    // Maximal distance to the player to start charging.
+    pub range: f32,
+    pub windup: f32,
+    pub speed: f32,
+    pub duration: f32,
+    pub cooldown: f32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ProjectileProfile {
+    pub speed: f32,
+    pub damage: usize,
+    pub radius: f32,
+    // Number of projectiles fired at once, spread evenly around the aimed direction.
+    #[serde(default = "default_projectile_count")]
+    pub count: usize,
+    // Angle between two neighbouring projectiles, in degrees.
+    #[serde(default)]
+    pub spread: f32,
+    // How far the projectiles fly.
+    #[serde(default = "default_projectile_range")]
+    pub range: f32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct AttackProfile {
+    // Maximal distance to the player to start attacking.
+    pub range: f32,
+    pub windup: f32,
+    pub cooldown: f32,
+    // Without a projectile, the attack only sends the EnemyAttack event.
+    #[serde(default)]
+    pub projectile: Option<ProjectileProfile>,
+}
+
+#[derive(Component, Clone, Copy, Serialize, Deserialize)]
+pub struct BehaviourProfile {
+    pub navigation: EnemyNavigation,
+    #[serde(default = "default_sight_range")]
+    pub sight_range: f32,
+    // For how long the enemy chases the player after losing sight of it.
+    #[serde(default = "default_memory")]
+    pub memory: f32,
+    #[serde(default)]
+    pub wander: bool,
+    // While chasing, the enemy does not come closer to the player than that.
+    #[serde(default)]
+    pub preferred_distance: f32,
+    // Fraction of the maximal health below which the enemy runs away.
+    #[serde(default)]
+    pub flee_below_health: Option<f32>,
+    #[serde(default)]
+    pub charge: Option<ChargeProfile>,
+    #[serde(default)]
+    pub attack: Option<AttackProfile>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EnemyState {
+    Idle,
+    Wander { direction: Vec2 },
+    Chase,
+    Flee,
+    ChargeWindup,
+    Charge { direction: Vec2 },
+    AttackWindup,
+}
+
+#[derive(Component)]
+pub struct EnemyStateMachine {
+    pub state: EnemyState,
+    // Time left until the current state ends on its own (windups, charges, wandering).
+    state_timer: f32,
+    charge_cooldown: f32,
+    attack_cooldown: f32,
+    // Time since the enemy has seen the player for the last time.
+    time_unseen: f32,
+}
+
+impl EnemyStateMachine {
+    pub fn new() -> EnemyStateMachine {
+        EnemyStateMachine {
+            state: EnemyState::Idle,
+            state_timer: 0.,
+            charge_cooldown: 0.,
+            attack_cooldown: 0.,
+            time_unseen: f32::INFINITY,
+        }
+    }
+
+    // Forces the enemy into the state for the given time, e.g. by scripted attacks.
+    // If the BehaviourProfile does not allow the state (e.g. has no charge for ChargeWindup),
+    // the enemy gets back to its usual behaviour once the state ends.
+    pub fn set(&mut self, state: EnemyState, duration: f32) {
+        self.state = state;
+        self.state_timer = duration;
+    }
+
+    fn tick(&mut self, delta: f32) {
+        self.state_timer -= delta;
+        self.charge_cooldown -= delta;
+        self.attack_cooldown -= delta;
+        self.time_unseen += delta;
+    }
+}
+
+/// Sent when an enemy finishes winding up an attack.
+pub struct EnemyAttack {
+    pub enemy: Entity,
+}
+
+fn random_direction(rng: &mut impl Rng) -> Vec2 {
+    let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+    Vec2::new(angle.cos(), angle.sin())
+}
+
+pub fn update_enemy_states(
+    mut enemies: Query<
+        (
+            Entity,
+            &Transform,
+            &HealthData,
+            &BehaviourProfile,
+            &mut EnemyStateMachine,
+        ),
+        With<EnemyMarker>,
+    >,
+    player: Query<&Transform, With<PlayerMarker>>,
+    collision_world: CollisionWorld,
+    mut attack_writer: EventWriter<EnemyAttack>,
+    mut rng: ResMut<GameRng>,
+) {
+    let player_position = match player.get_single() {
+        Ok(transform) => transform.translation.truncate(),
+        Err(_) => return,
+    };
+    let obstacles = CastFilter::new().with_layers(collision_world.layer(OBSTACLE_LAYER));
+
+    for (entity, transform, health, profile, mut machine) in enemies.iter_mut() {
+        machine.tick(SIMULATION_TICK);
+        let position = transform.translation.truncate();
+        let distance = position.distance(player_position);
+        if distance <= profile.sight_range
+            && collision_world.line_of_sight(position, player_position, &obstacles)
+        {
+            machine.time_unseen = 0.;
+        }
+        let sees_player = machine.time_unseen == 0.;
+        let health_fraction = health.current_health as f32 / health.max_health.max(1) as f32;
+
+        // Running away takes priority over everything else.
+        if profile
+            .flee_below_health
+            .is_some_and(|threshold| health_fraction < threshold)
+            && machine.time_unseen <= profile.memory
+        {
+            machine.set(EnemyState::Flee, 0.);
+            continue;
+        }
+
+        // States that have to finish first.
+        match machine.state {
+            EnemyState::ChargeWindup if machine.state_timer > 0. => continue,
+            // The state may have been forced (see EnemyStateMachine::set) on an enemy
+            // without a matching profile, then the enemy just goes back to chasing or idling.
+            EnemyState::ChargeWindup => {
+                if let Some(charge) = profile.charge {
+                    let direction = (player_position - position).normalize_or_zero();
+                    machine.set(EnemyState::Charge { direction }, charge.duration);
+                    continue;
+                }
+            }
+            EnemyState::Charge { .. } if machine.state_timer > 0. => continue,
+            EnemyState::Charge { .. } => {
+                if let Some(charge) = profile.charge {
+                    machine.charge_cooldown = charge.cooldown;
+                }
+            }
+            EnemyState::AttackWindup if machine.state_timer > 0. => continue,
+            EnemyState::AttackWindup => {
+                if let Some(attack) = profile.attack {
+                    machine.attack_cooldown = attack.cooldown;
+                    attack_writer.send(EnemyAttack { enemy: entity });
+                }
+            }
+            _ => (),
+        }
+
+        if sees_player {
+            if let Some(attack) = profile
+                .attack
+                .filter(|attack| distance <= attack.range && machine.attack_cooldown <= 0.)
+            {
+                machine.set(EnemyState::AttackWindup, attack.windup);
+                continue;
+            }
+            if let Some(charge) = profile
+                .charge
+                .filter(|charge| distance <= charge.range && machine.charge_cooldown <= 0.)
+            {
+                machine.set(EnemyState::ChargeWindup, charge.windup);
+                continue;
+            }
+        }
+
+        if machine.time_unseen <= profile.memory {
+            machine.set(EnemyState::Chase, 0.);
+        } else if !profile.wander {
+            machine.set(EnemyState::Idle, 0.);
+        } else if !matches!(machine.state, EnemyState::Wander { .. }) || machine.state_timer <= 0. {
+            let direction = random_direction(&mut *rng);
+            machine.set(EnemyState::Wander { direction }, WANDER_INTERVAL);
+        }
+    }
+}
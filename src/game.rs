@@ -1,4 +1,13 @@
+use crate::boss::BossPlugin;
+use crate::collision_world::CollisionWorldPlugin;
+use crate::game_over::GameOverPlugin;
 use crate::health_system::HealthPlugin;
+use crate::items::ItemPlugin;
+use crate::map::{MapStorage, RenderedMaps};
+use crate::navigation::NavigationPlugin;
+use crate::projectile::ProjectilePlugin;
+use crate::simulation::SimulationPlugin;
+use crate::waves::WavePlugin;
 use crate::{
     map, AppState, EnemyPlugin, LoadMap, MoveSystemPlugin, PlayerPlugin, RenderMap, UnrenderMap,
     WinSize,
@@ -12,11 +21,23 @@ impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
         app.add_system_set(SystemSet::on_enter(AppState::InGame).with_system(spawn_map))
             .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(spawn_camera))
-            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_map))
+            .add_system_set(
+                SystemSet::on_exit(AppState::InGame)
+                    .with_system(despawn_map)
+                    .with_system(despawn_camera),
+            )
+            .add_plugin(SimulationPlugin)
             .add_plugin(MoveSystemPlugin)
+            .add_plugin(CollisionWorldPlugin)
             .add_plugin(PlayerPlugin)
             .add_plugin(EnemyPlugin)
-            .add_plugin(HealthPlugin);
+            .add_plugin(BossPlugin)
+            .add_plugin(HealthPlugin)
+            .add_plugin(NavigationPlugin)
+            .add_plugin(WavePlugin)
+            .add_plugin(ItemPlugin)
+            .add_plugin(ProjectilePlugin)
+            .add_plugin(GameOverPlugin);
     }
 }
 
@@ -25,10 +46,33 @@ fn spawn_map(mut load_map: EventWriter<LoadMap>, mut render_map: EventWriter<Ren
     render_map.send(map::RenderMap(1));
 }
 
-fn despawn_map() {
-    todo!();
+// Maps are loaded again from the files in the next game.
+fn despawn_map(
+    rendered_maps: Res<RenderedMaps>,
+    mut maps: ResMut<MapStorage>,
+    mut unrender_map: EventWriter<UnrenderMap>,
+) {
+    for &id in rendered_maps.iter() {
+        unrender_map.send(map::UnrenderMap(id));
+    }
+    maps.clear();
 }
 
+#[derive(Component)]
+struct GameCamera;
+
 fn spawn_camera(mut commands: Commands) {
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+    commands
+        .spawn_bundle(OrthographicCameraBundle::new_2d())
+        .insert(GameCamera);
+    // For the in-game interface, e.g. health bars of bosses.
+    commands
+        .spawn_bundle(UiCameraBundle::default())
+        .insert(GameCamera);
+}
+
+fn despawn_camera(mut commands: Commands, cameras: Query<Entity, With<GameCamera>>) {
+    for camera in cameras.iter() {
+        commands.entity(camera).despawn();
+    }
 }
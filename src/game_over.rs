@@ -0,0 +1,276 @@
+use bevy::prelude::*;
+
+use crate::enemy::EnemyMarker;
+use crate::health_system::{DeathEvent, Invulnerability, ReadDeaths};
+use crate::items::Inventory;
+use crate::main_menu::ChangedButton;
+use crate::player::PlayerMarker;
+use crate::simulation::{run_in_state, SimulationAppExt, SIMULATION_TICK};
+use crate::waves::WaveDirector;
+use crate::AppState;
+
+/*
+    What happens when the player dies.
+
+    player_dies (in file player.rs) pushes AppState::Dying on top of InGame,
+    which freezes the game: the simulation only runs in InGame, but everything
+    stays where it was. During the death sequence the player spins, shrinks
+    and turns red, while the screen slowly darkens.
+
+    Then the whole state stack is replaced with AppState::GameOver, so the systems
+    registered on exiting InGame tear the game down. The game over screen shows
+    a summary of the run (RunStats) with buttons to play again or go back to the main menu.
+*/
+
+const DEATH_SEQUENCE_DURATION: f32 = 1.5;
+// How many times the player spins around while dying.
+const DEATH_SPINS: f32 = 2.;
+const DEATH_COLOR: Color = Color::rgb(0.8, 0.1, 0.1);
+const OVERLAY_COLOR: Color = Color::rgba(0., 0., 0., 0.7);
+const TEXT_COLOR: Color = Color::rgb(0.9, 0.9, 0.9);
+const BUTTON_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
+const BUTTON_HOVERED_COLOR: Color = Color::rgb(0.35, 0.35, 0.35);
+const BUTTON_PRESSED_COLOR: Color = Color::rgb(0.5, 0.5, 0.5);
+const FONT: &str = "QuattrocentoSans-Bold.ttf";
+
+pub struct GameOverPlugin;
+
+impl Plugin for GameOverPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(AppState::InGame).with_system(start_run))
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    .with_system(track_run)
+                    .with_system(count_defeated_enemies.label(ReadDeaths)),
+            )
+            .add_system_set(SystemSet::on_enter(AppState::Dying).with_system(start_death_sequence))
+            .add_system_set(SystemSet::on_update(AppState::Dying).with_system(play_death_sequence))
+            .add_system_set(SystemSet::on_exit(AppState::Dying).with_system(despawn_overlay))
+            .add_system_set(
+                SystemSet::on_enter(AppState::GameOver).with_system(show_game_over_screen),
+            )
+            .add_system_set(SystemSet::on_update(AppState::GameOver).with_system(game_over_buttons))
+            .add_system_set(
+                SystemSet::on_exit(AppState::GameOver).with_system(despawn_game_over_screen),
+            );
+    }
+}
+
+/// Summary of the current (or the last) run.
+#[derive(Default)]
+pub struct RunStats {
+    pub time: f32,
+    pub enemies_defeated: usize,
+    pub rooms_cleared: u32,
+    pub items_collected: usize,
+}
+
+// Time left until the end of the death sequence.
+struct DeathSequence {
+    remaining: f32,
+}
+
+#[derive(Component)]
+struct DeathOverlay;
+
+#[derive(Component)]
+struct GameOverScreen;
+
+#[derive(Component, Clone, Copy)]
+enum GameOverButton {
+    Restart,
+    MainMenu,
+}
+
+fn start_run(mut commands: Commands) {
+    commands.insert_resource(RunStats::default());
+}
+
+fn track_run(mut stats: ResMut<RunStats>) {
+    stats.time += SIMULATION_TICK;
+}
+
+fn count_defeated_enemies(
+    mut death_reader: EventReader<DeathEvent>,
+    enemies: Query<(), With<EnemyMarker>>,
+    mut stats: ResMut<RunStats>,
+) {
+    for death in death_reader.iter() {
+        if enemies.get(death.id).is_ok() {
+            stats.enemies_defeated += 1;
+        }
+    }
+}
+
+fn start_death_sequence(
+    mut commands: Commands,
+    mut players: Query<(Entity, &Inventory, &mut Visibility), With<PlayerMarker>>,
+    director: Res<WaveDirector>,
+    mut stats: ResMut<RunStats>,
+) {
+    commands.insert_resource(DeathSequence {
+        remaining: DEATH_SEQUENCE_DURATION,
+    });
+    stats.rooms_cleared = director.rooms_cleared();
+    for (player, inventory, mut visibility) in players.iter_mut() {
+        stats.items_collected = inventory.items().len();
+        // Otherwise the player could stay hidden in the middle of blinking.
+        commands.entity(player).remove::<Invulnerability>();
+        visibility.is_visible = true;
+    }
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(DeathOverlay);
+}
+
+fn play_death_sequence(
+    time: Res<Time>,
+    mut sequence: ResMut<DeathSequence>,
+    mut state: ResMut<State<AppState>>,
+    mut players: Query<(&mut Transform, &mut Sprite), With<PlayerMarker>>,
+    mut overlays: Query<&mut UiColor, With<DeathOverlay>>,
+) {
+    sequence.remaining -= time.delta_seconds();
+    let progress = (1. - sequence.remaining / DEATH_SEQUENCE_DURATION).clamp(0., 1.);
+
+    for (mut transform, mut sprite) in players.iter_mut() {
+        transform.rotation = Quat::from_rotation_z(progress * DEATH_SPINS * std::f32::consts::TAU);
+        transform.scale = Vec3::splat(1. - progress);
+        sprite.color = DEATH_COLOR;
+    }
+    for mut color in overlays.iter_mut() {
+        let mut overlay = OVERLAY_COLOR;
+        overlay.set_a(OVERLAY_COLOR.a() * progress);
+        color.0 = overlay;
+    }
+
+    if sequence.remaining <= 0. {
+        state
+            .replace(AppState::GameOver)
+            .expect("Couldn't switch state to GameOver");
+    }
+}
+
+fn despawn_overlay(mut commands: Commands, overlays: Query<Entity, With<DeathOverlay>>) {
+    for overlay in overlays.iter() {
+        commands.entity(overlay).despawn_recursive();
+    }
+    commands.remove_resource::<DeathSequence>();
+}
+
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn show_game_over_screen(
+    mut commands: Commands,
+    stats: Res<RunStats>,
+    asset_server: Res<AssetServer>,
+) {
+    let font = asset_server.load(FONT);
+    let text = |value: String, font_size: f32| TextBundle {
+        style: Style {
+            margin: Rect::all(Val::Px(6.0)),
+            ..Default::default()
+        },
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font: font.clone(),
+                font_size,
+                color: TEXT_COLOR,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    };
+    let summary = [
+        format!("Time: {}", format_time(stats.time)),
+        format!("Rooms cleared: {}", stats.rooms_cleared),
+        format!("Enemies defeated: {}", stats.enemies_defeated),
+        format!("Items collected: {}", stats.items_collected),
+    ];
+
+    commands
+        .spawn_bundle(UiCameraBundle::default())
+        .insert(GameOverScreen);
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..Default::default()
+            },
+            color: OVERLAY_COLOR.into(),
+            ..Default::default()
+        })
+        .insert(GameOverScreen)
+        .with_children(|parent| {
+            parent.spawn_bundle(text("Game Over".to_string(), 60.0));
+            for line in summary {
+                parent.spawn_bundle(text(line, 26.0));
+            }
+            for (button, label) in [
+                (GameOverButton::Restart, "Restart"),
+                (GameOverButton::MainMenu, "Main menu"),
+            ] {
+                parent
+                    .spawn_bundle(ButtonBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(220.0), Val::Px(56.0)),
+                            margin: Rect::all(Val::Px(8.0)),
+                            align_items: AlignItems::Center,
+                            justify_content: JustifyContent::Center,
+                            ..Default::default()
+                        },
+                        color: BUTTON_COLOR.into(),
+                        ..Default::default()
+                    })
+                    .insert(button)
+                    .with_children(|parent| {
+                        parent.spawn_bundle(text(label.to_string(), 32.0));
+                    });
+            }
+        });
+}
+
+fn game_over_buttons(
+    mut buttons: Query<(&Interaction, &GameOverButton, &mut UiColor), ChangedButton>,
+    mut state: ResMut<State<AppState>>,
+) {
+    for (interaction, button, mut color) in buttons.iter_mut() {
+        match interaction {
+            Interaction::Clicked => {
+                color.0 = BUTTON_PRESSED_COLOR;
+                let next = match button {
+                    GameOverButton::Restart => AppState::InGame,
+                    GameOverButton::MainMenu => AppState::MainMenu,
+                };
+                if let Err(error) = state.set(next) {
+                    eprintln!("[game_over_buttons] Couldn't switch state: {:?}", error);
+                }
+            }
+            Interaction::Hovered => color.0 = BUTTON_HOVERED_COLOR,
+            Interaction::None => color.0 = BUTTON_COLOR,
+        }
+    }
+}
+
+fn despawn_game_over_screen(mut commands: Commands, screen: Query<Entity, With<GameOverScreen>>) {
+    for entity in screen.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -1,7 +1,22 @@
+use crate::simulation::{run_in_state, SimulationAppExt, SIMULATION_TICK};
 use crate::AppState;
 use bevy::prelude::*;
 use std::cmp;
 use std::cmp::min;
+use std::collections::HashMap;
+
+/*
+    Health of entities, changed by TakeDamageEvent and HealEvent.
+    DeathEvent is sent when the health of an entity drops to zero.
+
+    Entities with Invulnerability ignore all damage for a while after being hurt,
+    and blink in the meantime. Entities with DamageCooldowns ignore damage
+    from a source (e.g. an enemy touching them) that has hurt them recently,
+    but can still be hurt by other sources.
+*/
+
+// How often invulnerable entities switch between visible and hidden.
+const BLINK_INTERVAL: f32 = 0.1;
 
 pub struct HealthPlugin;
 
@@ -10,10 +25,13 @@ impl Plugin for HealthPlugin {
         app.add_event::<TakeDamageEvent>();
         app.add_event::<DeathEvent>();
         app.add_event::<HealEvent>();
-        app.add_system_set(
-            SystemSet::on_update(AppState::InGame)
+        app.add_simulation_system_set(
+            SystemSet::new()
+                .with_run_criteria(run_in_state(AppState::InGame))
+                .with_system(tick_damage_timers.before(ModifyHealth))
                 .with_system(health_system.after(ModifyHealth).before(ReadDeaths)),
         );
+        app.add_system_to_stage(CoreStage::PostUpdate, blink_invulnerable);
     }
 }
 
@@ -26,6 +44,8 @@ pub struct ReadDeaths;
 pub struct TakeDamageEvent {
     pub id: Entity,
     pub amount: usize,
+    // What dealt the damage (e.g. an enemy or a bullet), if anything.
+    pub source: Option<Entity>,
 }
 
 pub struct HealEvent {
@@ -37,6 +57,52 @@ pub struct DeathEvent {
     pub id: Entity,
 }
 
+// Damage dealt to the player when it touches the entity.
+#[derive(Component, Clone, Copy)]
+pub struct ContactDamage(pub usize);
+
+// Damage dealt by a projectile to whatever it hits.
+#[derive(Component, Clone, Copy)]
+pub struct Damage(pub usize);
+
+// After taking damage, the entity cannot be hurt for the given time.
+#[derive(Component, Clone, Copy)]
+pub struct Invulnerability {
+    pub duration: f32,
+    remaining: f32,
+}
+
+impl Invulnerability {
+    pub fn new(duration: f32) -> Invulnerability {
+        Invulnerability {
+            duration,
+            remaining: 0.,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining > 0.
+    }
+}
+
+// After taking damage from a source, the entity cannot be hurt
+// by the same source for the given time.
+#[derive(Component, Clone)]
+pub struct DamageCooldowns {
+    pub cooldown: f32,
+    // Time left until every source can deal damage again.
+    remaining: HashMap<Entity, f32>,
+}
+
+impl DamageCooldowns {
+    pub fn new(cooldown: f32) -> DamageCooldowns {
+        DamageCooldowns {
+            cooldown,
+            remaining: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Component, Clone)]
 pub struct HealthData {
     pub max_health: usize,
@@ -68,20 +134,53 @@ impl HealthData {
     }
 }
 
+fn tick_damage_timers(
+    mut invulnerable: Query<&mut Invulnerability>,
+    mut cooldowns: Query<&mut DamageCooldowns>,
+) {
+    for mut invulnerability in invulnerable.iter_mut() {
+        invulnerability.remaining -= SIMULATION_TICK;
+    }
+    for mut cooldowns in cooldowns.iter_mut() {
+        cooldowns.remaining.retain(|_, remaining| {
+            *remaining -= SIMULATION_TICK;
+            *remaining > 0.
+        });
+    }
+}
+
 fn health_system(
     mut damage_reader: EventReader<TakeDamageEvent>,
     mut heal_reader: EventReader<HealEvent>,
     mut death_writer: EventWriter<DeathEvent>,
-    mut entities: Query<(&mut HealthData)>,
+    mut entities: Query<(
+        &mut HealthData,
+        Option<&mut Invulnerability>,
+        Option<&mut DamageCooldowns>,
+    )>,
 ) {
     for heal_event in heal_reader.iter() {
-        if let Ok(mut to_heal) = entities.get_mut(heal_event.id) {
+        if let Ok((mut to_heal, _, _)) = entities.get_mut(heal_event.id) {
             to_heal.heal(heal_event.amount);
         }
     }
 
     for damage_event in damage_reader.iter() {
-        if let Ok(mut to_damage) = entities.get_mut(damage_event.id) {
+        if let Ok((mut to_damage, invulnerability, cooldowns)) = entities.get_mut(damage_event.id) {
+            if invulnerability.as_ref().is_some_and(|i| i.is_active()) {
+                continue;
+            }
+            if let (Some(source), Some(mut cooldowns)) = (damage_event.source, cooldowns) {
+                if cooldowns.remaining.contains_key(&source) {
+                    continue;
+                }
+                let cooldown = cooldowns.cooldown;
+                cooldowns.remaining.insert(source, cooldown);
+            }
+            if let Some(mut invulnerability) = invulnerability {
+                invulnerability.remaining = invulnerability.duration;
+            }
+
             if to_damage.take_damage(damage_event.amount) {
                 death_writer.send(DeathEvent {
                     id: damage_event.id,
@@ -90,3 +189,13 @@ fn health_system(
         }
     }
 }
+
+fn blink_invulnerable(mut entities: Query<(&Invulnerability, &mut Visibility)>) {
+    for (invulnerability, mut visibility) in entities.iter_mut() {
+        let is_visible = !invulnerability.is_active()
+            || ((invulnerability.remaining / BLINK_INTERVAL) as u32).is_multiple_of(2);
+        if visibility.is_visible != is_visible {
+            visibility.is_visible = is_visible;
+        }
+    }
+}
@@ -1,11 +1,31 @@
 use bevy::prelude::*;
-use bevy::sprite::collide_aabb::collide;
 use serde::{Deserialize, Serialize};
 
 #[derive(Component, Clone, Copy, Serialize, Deserialize)]
 pub enum Hitbox {
     Rectangle(Vec2),
-    Circle(f32), // currently not used
+    Circle(f32),
+}
+
+/// Information about how two hitboxes overlap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Contact {
+    /// Unit vector pointing from the first hitbox towards the second one.
+    pub normal: Vec2,
+    /// How far the hitboxes have to be moved apart along the normal to stop overlapping.
+    pub depth: f32,
+    /// Point in the middle of the overlapping area.
+    pub point: Vec2,
+}
+
+impl Contact {
+    // The same contact seen from the second hitbox.
+    pub fn flipped(&self) -> Contact {
+        Contact {
+            normal: -self.normal,
+            ..*self
+        }
+    }
 }
 
 impl Hitbox {
@@ -15,19 +35,377 @@ impl Hitbox {
         y_hitbox: &Hitbox,
         y_position: Vec2,
     ) -> bool {
+        Hitbox::contact(x_hitbox, x_position, y_hitbox, y_position).is_some()
+    }
+
+    // Returns None if the hitboxes do not overlap. Touching hitboxes do not overlap.
+    pub fn contact(
+        x_hitbox: &Hitbox,
+        x_position: Vec2,
+        y_hitbox: &Hitbox,
+        y_position: Vec2,
+    ) -> Option<Contact> {
         match (x_hitbox, y_hitbox) {
             (&Hitbox::Rectangle(dim_x), &Hitbox::Rectangle(dim_y)) => {
-                collide(x_position.extend(0.), dim_x, y_position.extend(0.), dim_y).is_some()
+                let delta = y_position - x_position;
+                let overlap = (dim_x + dim_y) / 2. - delta.abs();
+                if overlap.x <= 0. || overlap.y <= 0. {
+                    return None;
+                }
+                // Separate the rectangles along the axis on which they overlap less.
+                let (normal, depth) = if overlap.x < overlap.y {
+                    (Vec2::new(sign(delta.x), 0.), overlap.x)
+                } else {
+                    (Vec2::new(0., sign(delta.y)), overlap.y)
+                };
+                let min = (x_position - dim_x / 2.).max(y_position - dim_y / 2.);
+                let max = (x_position + dim_x / 2.).min(y_position + dim_y / 2.);
+                Some(Contact {
+                    normal,
+                    depth,
+                    point: (min + max) / 2.,
+                })
+            }
+            (&Hitbox::Circle(r_x), &Hitbox::Circle(r_y)) => {
+                let delta = y_position - x_position;
+                let distance = delta.length();
+                let depth = r_x + r_y - distance;
+                if depth <= 0. {
+                    return None;
+                }
+                let normal = if distance > 0. {
+                    delta / distance
+                } else {
+                    Vec2::X
+                };
+                Some(Contact {
+                    normal,
+                    depth,
+                    point: x_position + normal * (r_x - depth / 2.),
+                })
+            }
+            (&Hitbox::Rectangle(dim_x), &Hitbox::Circle(r_y)) => {
+                // The point of the rectangle closest to the center of the circle.
+                let half_extents = dim_x / 2.;
+                let closest =
+                    y_position.clamp(x_position - half_extents, x_position + half_extents);
+                let delta = y_position - closest;
+                let distance = delta.length();
+                if distance > 0. {
+                    let depth = r_y - distance;
+                    return (depth > 0.).then(|| Contact {
+                        normal: delta / distance,
+                        depth,
+                        point: closest,
+                    });
+                }
+                // The center of the circle is inside the rectangle,
+                // so push it out through the nearest side.
+                let offset = y_position - x_position;
+                let to_side = half_extents - offset.abs();
+                let (normal, distance_to_side) = if to_side.x < to_side.y {
+                    (Vec2::new(sign(offset.x), 0.), to_side.x)
+                } else {
+                    (Vec2::new(0., sign(offset.y)), to_side.y)
+                };
+                Some(Contact {
+                    normal,
+                    depth: r_y + distance_to_side,
+                    point: y_position + normal * distance_to_side,
+                })
             }
-            (&Hitbox::Circle(r_x), &Hitbox::Circle(r_y)) => todo!(),
-            (&Hitbox::Rectangle(dim_x), &Hitbox::Circle(r_y)) => todo!(),
             (&Hitbox::Circle(_), &Hitbox::Rectangle(_)) => {
-                Hitbox::check_collision(y_hitbox, y_position, x_hitbox, x_position)
+                Hitbox::contact(y_hitbox, y_position, x_hitbox, x_position)
+                    .map(|contact| contact.flipped())
             }
         }
     }
 
+    // Casts a ray from the origin in the given (normalized) direction and returns
+    // the distance at which it enters the hitbox, if it does so within max_distance.
+    // A ray starting inside the hitbox hits it at distance 0.
+    pub fn raycast(
+        &self,
+        position: Vec2,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+    ) -> Option<f32> {
+        let distance = match *self {
+            Hitbox::Rectangle(dim) => {
+                // Slab test: intersect the ray with both pairs of parallel sides.
+                let min = position - dim / 2.;
+                let max = position + dim / 2.;
+                let mut t_enter = 0f32;
+                let mut t_exit = max_distance;
+                for axis in 0..2 {
+                    if direction[axis] == 0. {
+                        if origin[axis] <= min[axis] || origin[axis] >= max[axis] {
+                            return None;
+                        }
+                    } else {
+                        let t_1 = (min[axis] - origin[axis]) / direction[axis];
+                        let t_2 = (max[axis] - origin[axis]) / direction[axis];
+                        t_enter = t_enter.max(t_1.min(t_2));
+                        t_exit = t_exit.min(t_1.max(t_2));
+                    }
+                }
+                (t_enter < t_exit).then_some(t_enter)?
+            }
+            Hitbox::Circle(r) => {
+                let offset = origin - position;
+                let c = offset.length_squared() - r * r;
+                if c < 0. {
+                    return Some(0.);
+                }
+                let b = offset.dot(direction);
+                let discriminant = b * b - c;
+                if b > 0. || discriminant <= 0. {
+                    return None;
+                }
+                -b - discriminant.sqrt()
+            }
+        };
+        (distance <= max_distance).then_some(distance)
+    }
+
+    // Moves the shape from the origin in the given (normalized) direction and returns
+    // the distance after which it starts to overlap the target, if it does so within max_distance.
+    pub fn shapecast(
+        shape: &Hitbox,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        target: &Hitbox,
+        target_position: Vec2,
+    ) -> Option<f32> {
+        // Casting a shape is the same as casting a ray against
+        // the target inflated by the shape (their Minkowski sum).
+        let raycast = |hitbox: Hitbox, position: Vec2| {
+            hitbox.raycast(position, origin, direction, max_distance)
+        };
+        match (*shape, *target) {
+            (Hitbox::Rectangle(dim_s), Hitbox::Rectangle(dim_t)) => {
+                raycast(Hitbox::Rectangle(dim_s + dim_t), target_position)
+            }
+            (Hitbox::Circle(r_s), Hitbox::Circle(r_t)) => {
+                raycast(Hitbox::Circle(r_s + r_t), target_position)
+            }
+            (Hitbox::Rectangle(dim), Hitbox::Circle(r))
+            | (Hitbox::Circle(r), Hitbox::Rectangle(dim)) => {
+                // Rectangle with rounded corners.
+                let half_extents = dim / 2.;
+                let corners = [
+                    Vec2::new(half_extents.x, half_extents.y),
+                    Vec2::new(-half_extents.x, half_extents.y),
+                    Vec2::new(half_extents.x, -half_extents.y),
+                    Vec2::new(-half_extents.x, -half_extents.y),
+                ];
+                let sides = [
+                    raycast(
+                        Hitbox::Rectangle(dim + Vec2::new(2. * r, 0.)),
+                        target_position,
+                    ),
+                    raycast(
+                        Hitbox::Rectangle(dim + Vec2::new(0., 2. * r)),
+                        target_position,
+                    ),
+                ];
+                sides
+                    .into_iter()
+                    .chain(
+                        corners
+                            .iter()
+                            .map(|&corner| raycast(Hitbox::Circle(r), target_position + corner)),
+                    )
+                    .flatten()
+                    .reduce(f32::min)
+            }
+        }
+    }
+
+    // Half of the size of the smallest axis-aligned box containing the hitbox.
+    pub fn half_extents(&self) -> Vec2 {
+        match *self {
+            Hitbox::Rectangle(dim) => dim / 2.,
+            Hitbox::Circle(r) => Vec2::splat(r),
+        }
+    }
+
+    // Size of the smallest axis-aligned box containing the hitbox,
+    // e.g. to use as a custom size of a sprite.
+    pub fn size(&self) -> Vec2 {
+        self.half_extents() * 2.
+    }
+
     pub fn new_rectangle(dimensions: Vec2) -> Hitbox {
         Hitbox::Rectangle(dimensions)
     }
+
+    pub fn new_circle(radius: f32) -> Hitbox {
+        Hitbox::Circle(radius)
+    }
+}
+
+// Like f32::signum, but treats zero as positive, so the result is never zero.
+fn sign(x: f32) -> f32 {
+    if x < 0. {
+        -1.
+    } else {
+        1.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether the hitboxes collide, with the first one at the origin.
+    fn collide(x_hitbox: &Hitbox, y_hitbox: &Hitbox, y_position: Vec2) -> bool {
+        Hitbox::check_collision(x_hitbox, Vec2::ZERO, y_hitbox, y_position)
+    }
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("expected a hit");
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn touching_hitboxes_do_not_collide() {
+        let circle = Hitbox::new_circle(5.);
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        assert!(collide(&circle, &circle, Vec2::new(9., 0.)));
+        assert!(!collide(&circle, &circle, Vec2::new(10., 0.)));
+        assert!(collide(&square, &circle, Vec2::new(9., 0.)));
+        assert!(!collide(&square, &circle, Vec2::new(10., 0.)));
+        assert!(!collide(&square, &square, Vec2::new(0., 10.)));
+    }
+
+    #[test]
+    fn circles_do_not_collide_with_corners_of_their_bounding_boxes() {
+        let circle = Hitbox::new_circle(2.);
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        // The bounding boxes overlap, the shapes do not.
+        assert!(!collide(&square, &circle, Vec2::new(6.5, 6.5)));
+        assert!(!collide(&circle, &circle, Vec2::new(3.5, 3.5)));
+        assert!(collide(&circle, &square, Vec2::new(6.5, 0.)));
+    }
+
+    #[test]
+    fn rectangles_separate_along_the_smaller_overlap() {
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        let contact = Hitbox::contact(&square, Vec2::ZERO, &square, Vec2::new(8., 1.)).unwrap();
+        assert_eq!(contact.normal, Vec2::X);
+        assert_eq!(contact.depth, 2.);
+        assert_eq!(contact.point, Vec2::new(4., 0.5));
+
+        let contact = Hitbox::contact(&square, Vec2::ZERO, &square, Vec2::new(1., -8.)).unwrap();
+        assert_eq!(contact.normal, -Vec2::Y);
+        assert_eq!(contact.depth, 2.);
+    }
+
+    #[test]
+    fn circles_separate_along_the_line_between_centres() {
+        let circle = Hitbox::new_circle(5.);
+        let contact = Hitbox::contact(&circle, Vec2::ZERO, &circle, Vec2::new(0., 8.)).unwrap();
+        assert_eq!(contact.normal, Vec2::Y);
+        assert_eq!(contact.depth, 2.);
+        assert_eq!(contact.point, Vec2::new(0., 4.));
+
+        // Circles at the same spot still get a valid normal.
+        let contact = Hitbox::contact(&circle, Vec2::ZERO, &circle, Vec2::ZERO).unwrap();
+        assert_eq!(contact.normal, Vec2::X);
+        assert_eq!(contact.depth, 10.);
+    }
+
+    #[test]
+    fn circles_separate_from_the_closest_point_of_rectangles() {
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        let circle = Hitbox::new_circle(2.);
+        let contact = Hitbox::contact(&square, Vec2::ZERO, &circle, Vec2::new(6., 0.)).unwrap();
+        assert_eq!(contact.normal, Vec2::X);
+        assert_eq!(contact.depth, 1.);
+        assert_eq!(contact.point, Vec2::new(5., 0.));
+
+        // The normal always points from the first hitbox towards the second one.
+        let contact = Hitbox::contact(&circle, Vec2::new(6., 0.), &square, Vec2::ZERO).unwrap();
+        assert_eq!(contact.normal, -Vec2::X);
+        assert_eq!(contact.depth, 1.);
+    }
+
+    #[test]
+    fn circles_inside_rectangles_are_pushed_out_through_the_nearest_side() {
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        let circle = Hitbox::new_circle(2.);
+        let contact = Hitbox::contact(&square, Vec2::ZERO, &circle, Vec2::new(0., -3.)).unwrap();
+        assert_eq!(contact.normal, -Vec2::Y);
+        assert_eq!(contact.depth, 4.);
+        assert_eq!(contact.point, Vec2::new(0., -5.));
+    }
+
+    #[test]
+    fn rays_hit_rectangles_at_their_sides() {
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        let position = Vec2::new(20., 0.);
+        assert_close(square.raycast(position, Vec2::ZERO, Vec2::X, 100.), 15.);
+        assert_close(
+            square.raycast(position, Vec2::new(20., -10.), Vec2::Y, 100.),
+            5.,
+        );
+        // Too short, pointing away, passing by.
+        assert_eq!(square.raycast(position, Vec2::ZERO, Vec2::X, 10.), None);
+        assert_eq!(square.raycast(position, Vec2::ZERO, -Vec2::X, 100.), None);
+        assert_eq!(square.raycast(position, Vec2::ZERO, Vec2::Y, 100.), None);
+        // Starting inside.
+        assert_close(square.raycast(position, position, Vec2::X, 100.), 0.);
+    }
+
+    #[test]
+    fn rays_hit_circles_at_their_edges() {
+        let circle = Hitbox::new_circle(5.);
+        let position = Vec2::new(20., 0.);
+        assert_close(circle.raycast(position, Vec2::ZERO, Vec2::X, 100.), 15.);
+        let diagonal = Vec2::new(3., 4.) / 5.;
+        assert_close(
+            circle.raycast(Vec2::new(6., 8.), Vec2::ZERO, diagonal, 100.),
+            5.,
+        );
+        assert_eq!(circle.raycast(position, Vec2::ZERO, -Vec2::X, 100.), None);
+        assert_eq!(
+            circle.raycast(Vec2::new(20., 6.), Vec2::ZERO, Vec2::X, 100.),
+            None
+        );
+        assert_close(circle.raycast(position, position, Vec2::X, 100.), 0.);
+    }
+
+    #[test]
+    fn shapes_stop_where_they_start_to_overlap_the_target() {
+        let square = Hitbox::new_rectangle(Vec2::splat(10.));
+        let small_square = Hitbox::new_rectangle(Vec2::splat(2.));
+        let circle = Hitbox::new_circle(1.);
+        let position = Vec2::new(20., 0.);
+        let cast = |shape: &Hitbox, target: &Hitbox, direction: Vec2| {
+            Hitbox::shapecast(shape, Vec2::ZERO, direction, 100., target, position)
+        };
+        assert_close(cast(&small_square, &square, Vec2::X), 14.);
+        assert_close(cast(&circle, &square, Vec2::X), 14.);
+        assert_close(cast(&square, &circle, Vec2::X), 14.);
+        assert_close(cast(&circle, &Hitbox::new_circle(5.), Vec2::X), 14.);
+        assert_eq!(cast(&circle, &square, Vec2::Y), None);
+
+        // Circles pass closer to the corners of rectangles than rectangles do.
+        let corner = Vec2::new(15., 5.);
+        let from = Vec2::new(0., 6.5);
+        let towards_corner = (corner - from).normalize();
+        let expected = (corner - from).length() - 1.;
+        assert_close(
+            Hitbox::shapecast(&circle, from, towards_corner, 100., &square, position),
+            expected,
+        );
+    }
 }
@@ -0,0 +1,369 @@
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::collision_layers::CollisionMatrix;
+use crate::hitbox::Hitbox;
+use crate::move_system::{CollisionEvent, HandleCollisionEvents, MoveSystemObject};
+use crate::player::{PlayerMarker, PlayerStats};
+use crate::projectile::{ProjectileModifier, ProjectileModifiers};
+use crate::simulation::{run_in_state, GameRng, SimulationAppExt};
+use crate::spawn_placement::SpawnPlacement;
+use crate::waves::{DirectWaves, RoomCleared};
+use crate::AppState;
+
+/*
+    Items - pickups that make the player stronger, read from JSON files in ITEMS_PATH.
+
+    An item lies in a room until the player touches it (its collision layer is Pickup,
+    which only reports collisions with the player, so nothing is blocked by it).
+    Collected items are kept in the Inventory of the player, and the PlayerStats
+    are recomputed from the starting stats and the modifiers of all of them.
+
+    Example of a file:
+        {
+            "id": "coffee",
+            "name": "Coffee",
+            "texture": "tear.png",
+            "color": [0.6, 0.4, 0.2],
+            "weight": 1.0,
+            "modifiers": [
+                { "stat": "FireDelay", "multiply": 0.8 },
+                { "stat": "Speed", "add": 10.0 }
+            ],
+            "projectile": [ { "Homing": { "turn_rate": 3.0 } } ]
+        }
+
+    Items can also change the bullets of the player with "projectile" modifiers
+    (description in file projectile.rs).
+
+    Modifiers stack: first all "add"s of a stat are summed up and added to it,
+    then it is multiplied by all "multiply"s. The color tints the texture
+    (white by default). Items with a bigger weight are chosen more often,
+    but the luck of the player evens the weights out, so rare items
+    drop more often (with luck L, a weight W counts as W^(1 / (1 + L))).
+
+    Send SpawnItem to place an item in the room. Every cleared room
+    leaves a random item behind as a reward.
+*/
+
+const ITEMS_PATH: &str = "data/items";
+const ITEM_SIZE: f32 = 20.;
+const PICKUP_LAYER: &str = "Pickup";
+// Shooting faster than that would flood the room with bullets.
+const MIN_FIRE_DELAY: f32 = 0.05;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+struct RewardClearedRooms;
+
+pub struct ItemPlugin;
+
+impl Plugin for ItemPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SpawnItem>()
+            .add_startup_system(load_items)
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(despawn_items))
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    // Items are rolled and placed in the same tick the room is cleared.
+                    .with_system(
+                        reward_cleared_rooms
+                            .label(RewardClearedRooms)
+                            .after(DirectWaves),
+                    )
+                    .with_system(spawn_items.after(RewardClearedRooms))
+                    .with_system(pick_up_items.label(HandleCollisionEvents)),
+            );
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stat {
+    Speed,
+    Damage,
+    FireDelay,
+    ShotSpeed,
+    Range,
+    Luck,
+}
+
+fn default_factor() -> f32 {
+    1.
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct StatModifier {
+    pub stat: Stat,
+    #[serde(default)]
+    pub add: f32,
+    #[serde(default = "default_factor")]
+    pub multiply: f32,
+}
+
+fn default_color() -> [f32; 3] {
+    [1., 1., 1.]
+}
+
+fn default_weight() -> f32 {
+    1.
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ItemDefinition {
+    pub id: String,
+    pub name: String,
+    pub texture: String,
+    #[serde(default = "default_color")]
+    pub color: [f32; 3],
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    pub modifiers: Vec<StatModifier>,
+    #[serde(default)]
+    pub projectile: Vec<ProjectileModifier>,
+    // Filled in after loading the file.
+    #[serde(skip)]
+    pub texture_handle: Handle<Image>,
+}
+
+/// Definitions of items by their IDs.
+pub struct ItemDefinitions {
+    items: HashMap<String, ItemDefinition>,
+}
+
+impl ItemDefinitions {
+    pub fn get(&self, id: &str) -> Option<&ItemDefinition> {
+        self.items.get(id)
+    }
+
+    // Chooses an item at random, according to the weights evened out by the luck.
+    pub fn choose(&self, luck: f32, rng: &mut impl Rng) -> Option<&ItemDefinition> {
+        let exponent = 1. / (1. + luck.max(0.));
+        let weight = |item: &ItemDefinition| item.weight.max(0.).powf(exponent);
+        // The order of a HashMap differs between runs, the choice should not.
+        let mut items: Vec<&ItemDefinition> = self.items.values().collect();
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        let total_weight: f32 = items.iter().copied().map(weight).sum();
+        if total_weight <= 0. {
+            return None;
+        }
+        let mut roll = rng.gen_range(0.0..total_weight);
+        items
+            .iter()
+            .copied()
+            .find(|item| {
+                roll -= weight(item);
+                roll < 0.
+            })
+            .or_else(|| items.last().copied())
+    }
+}
+
+/// Message for asking to place the item of a given ID in the room.
+/// Without a position, it is placed anywhere, but not too close to the player.
+pub struct SpawnItem {
+    pub id: String,
+    pub position: Option<Vec2>,
+}
+
+#[derive(Component, Clone)]
+pub struct ItemPickup {
+    pub id: String,
+}
+
+/// Items collected by the player.
+#[derive(Component, Default)]
+pub struct Inventory {
+    items: Vec<String>,
+    modifiers: Vec<StatModifier>,
+    projectile: Vec<ProjectileModifier>,
+}
+
+impl Inventory {
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    pub fn add(&mut self, item: &ItemDefinition) {
+        self.items.push(item.id.clone());
+        self.modifiers.extend(item.modifiers.iter().copied());
+        self.projectile.extend(item.projectile.iter().copied());
+    }
+
+    pub fn projectile_modifiers(&self) -> ProjectileModifiers {
+        let mut modifiers = ProjectileModifiers::default();
+        for &modifier in &self.projectile {
+            modifiers.add(modifier);
+        }
+        modifiers
+    }
+
+    // Starting stats of the player with the modifiers of all items applied.
+    pub fn stats(&self) -> PlayerStats {
+        let mut stats = PlayerStats::default();
+        let fields = [
+            (Stat::Speed, &mut stats.speed),
+            (Stat::Damage, &mut stats.damage),
+            (Stat::FireDelay, &mut stats.fire_delay),
+            (Stat::ShotSpeed, &mut stats.shot_speed),
+            (Stat::Range, &mut stats.range),
+            (Stat::Luck, &mut stats.luck),
+        ];
+        for (stat, value) in fields {
+            let modifiers = self
+                .modifiers
+                .iter()
+                .filter(|modifier| modifier.stat == stat);
+            let added: f32 = modifiers.clone().map(|modifier| modifier.add).sum();
+            let factor: f32 = modifiers.map(|modifier| modifier.multiply).product();
+            *value = (*value + added) * factor;
+        }
+
+        stats.speed = stats.speed.max(0.);
+        stats.damage = stats.damage.max(0.);
+        stats.fire_delay = stats.fire_delay.max(MIN_FIRE_DELAY);
+        stats.shot_speed = stats.shot_speed.max(0.);
+        stats.range = stats.range.max(0.);
+        stats
+    }
+}
+
+fn read_item(path: &std::path::Path) -> Option<ItemDefinition> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn load_items(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let mut items = HashMap::new();
+    let entries = fs::read_dir(ITEMS_PATH).expect("Couldn't read the items directory");
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if let Some(mut item) = read_item(&path) {
+            item.texture_handle = asset_server.load(item.texture.as_str());
+            items.insert(item.id.clone(), item);
+        } else {
+            eprintln!("[load_items] Incorrect item file {}.", path.display());
+        }
+    }
+    commands.insert_resource(ItemDefinitions { items });
+}
+
+pub fn spawn_item(
+    commands: &mut Commands,
+    collision_matrix: &CollisionMatrix,
+    item: &ItemDefinition,
+    position: Vec2,
+) -> Entity {
+    let [red, green, blue] = item.color;
+    commands
+        .spawn_bundle(MoveSystemObject::new(
+            collision_matrix.layers(PICKUP_LAYER),
+            Hitbox::new_circle(ITEM_SIZE / 2.),
+        ))
+        .insert(ItemPickup {
+            id: item.id.clone(),
+        })
+        .insert_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgb(red, green, blue),
+                custom_size: Some(Vec2::splat(ITEM_SIZE)),
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: position.extend(1.0),
+                ..Default::default()
+            },
+            texture: item.texture_handle.clone(),
+            ..Default::default()
+        })
+        .id()
+}
+
+fn spawn_items(
+    mut commands: Commands,
+    mut spawn_event: EventReader<SpawnItem>,
+    placement: SpawnPlacement,
+    definitions: Res<ItemDefinitions>,
+    collision_matrix: Res<CollisionMatrix>,
+    mut rng: ResMut<GameRng>,
+) {
+    let mut taken = vec![];
+    for SpawnItem { id, position } in spawn_event.iter() {
+        let item = match definitions.get(id) {
+            Some(item) => item,
+            None => {
+                eprintln!("[spawn_items] There is no item {}.", id);
+                continue;
+            }
+        };
+        let hitbox = Hitbox::new_circle(ITEM_SIZE / 2.);
+        let position = match position.or_else(|| placement.anywhere(&hitbox, &mut taken, &mut *rng))
+        {
+            Some(position) => position,
+            None => {
+                eprintln!("[spawn_items] There is no place for the item {}.", id);
+                continue;
+            }
+        };
+        spawn_item(&mut commands, &collision_matrix, item, position);
+    }
+}
+
+fn reward_cleared_rooms(
+    mut cleared_reader: EventReader<RoomCleared>,
+    mut item_writer: EventWriter<SpawnItem>,
+    definitions: Res<ItemDefinitions>,
+    players: Query<&PlayerStats, With<PlayerMarker>>,
+    mut rng: ResMut<GameRng>,
+) {
+    let luck = players.get_single().map_or(0., |stats| stats.luck);
+    for _ in cleared_reader.iter() {
+        if let Some(item) = definitions.choose(luck, &mut *rng) {
+            item_writer.send(SpawnItem {
+                id: item.id.clone(),
+                position: None,
+            });
+        }
+    }
+}
+
+fn despawn_items(mut commands: Commands, items: Query<Entity, With<ItemPickup>>) {
+    for item in items.iter() {
+        commands.entity(item).despawn();
+    }
+}
+
+fn pick_up_items(
+    mut commands: Commands,
+    mut collision_reader: EventReader<CollisionEvent>,
+    mut players: Query<
+        (&mut Inventory, &mut PlayerStats, &mut ProjectileModifiers),
+        With<PlayerMarker>,
+    >,
+    pickups: Query<&ItemPickup>,
+    definitions: Res<ItemDefinitions>,
+) {
+    let mut collected = vec![];
+    // Only the player touching an item matters, both queries check that.
+    for collision in collision_reader.iter() {
+        // The player may touch an item more than once before it disappears.
+        if collected.contains(&collision.collided_with_id) {
+            continue;
+        }
+        if let (Ok((mut inventory, mut stats, mut modifiers)), Ok(pickup)) = (
+            players.get_mut(collision.object_id),
+            pickups.get(collision.collided_with_id),
+        ) {
+            if let Some(item) = definitions.get(&pickup.id) {
+                info!("Picked up {}.", item.name);
+                inventory.add(item);
+                *stats = inventory.stats();
+                *modifiers = inventory.projectile_modifiers();
+            }
+            commands.entity(collision.collided_with_id).despawn();
+            collected.push(collision.collided_with_id);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(unused)]
+// Triggered by the code generated by Bevy's derive(Bundle).
+#![allow(clippy::forget_non_drop)]
 
-use std::time::Duration;
 // For the basic funcionality of Bevy.
 use bevy::prelude::*;
 // For setting up the minimum acceptable size of the window.
@@ -10,23 +11,36 @@ use crate::hitbox::Hitbox;
 use crate::window::WinSize;
 use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
 
+mod boss;
+mod collision_layers;
+mod collision_world;
 mod common;
 mod enemy;
+mod enemy_archetype;
+mod enemy_behaviour;
 mod game;
+mod game_over;
 mod health_system;
 mod hitbox;
+mod items;
 mod main_menu;
 mod map;
 mod move_system;
+mod navigation;
 mod parser;
 mod player;
+mod projectile;
+mod simulation;
+mod spatial_hash;
+mod spawn_placement;
+mod steering;
+mod waves;
 mod window;
 
 use crate::common::load_textures;
 use crate::enemy::EnemyPlugin;
 use crate::game::GamePlugin;
 use crate::main_menu::MainMenuPlugin;
-use crate::move_system::MoveObjectType::Player;
 use crate::move_system::MoveSystemPlugin;
 use crate::player::{PlayerBundle, PlayerPlugin};
 use map::{LoadMap, MapPlugin, RenderMap, UnloadMap, UnrenderMap};
@@ -49,6 +63,9 @@ const WINDOW_MAX_HEIGHT: f32 = f32::INFINITY;
 enum AppState {
     MainMenu,
     InGame,
+    // Pushed on top of InGame when the player dies, so the game freezes.
+    Dying,
+    GameOver,
 }
 
 /*===================
@@ -56,10 +73,6 @@ enum AppState {
 ====================*/
 
 fn main() {
-    // Adding timer to resources.
-    let mut timer = Timer::from_seconds(0.5, false);
-    timer.tick(Duration::new(1, 0));
-
     App::new()
         .insert_resource(Msaa { samples: 4 }) // ???
         .add_state(AppState::MainMenu)
@@ -79,15 +92,13 @@ fn main() {
             width: WINDOW_WIDTH,
             height: WINDOW_HEIGHT,
         })
-        .insert_resource(timer)
         .add_plugins(DefaultPlugins)
         // Diagnostic information about framerate.
         .add_plugin(LogDiagnosticsPlugin::default())
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(FrameTimeDiagnosticsPlugin)
         // Custom plugins.
         .add_plugin(MainMenuPlugin)
         .add_plugin(GamePlugin)
-        .add_plugin(EnemyPlugin)
         .add_startup_system_to_stage(StartupStage::PreStartup, load_textures)
         .add_system(resize_window)
         .add_plugin(MapPlugin)
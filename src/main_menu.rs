@@ -14,14 +14,19 @@ struct UiCamera {
     camera_entity: Entity,
 }
 
+// Buttons whose interaction has just changed.
+pub type ChangedButton = (Changed<Interaction>, With<Button>);
+
 pub struct MainMenuPlugin;
 
 impl Plugin for MainMenuPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_menu)
+        app.add_system_set(SystemSet::on_enter(AppState::MainMenu).with_system(setup_menu))
             .add_system_set(SystemSet::on_exit(AppState::MainMenu).with_system(despawn_menu))
             .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(success))
-            .add_system(button_press_system);
+            .add_system_set(
+                SystemSet::on_update(AppState::MainMenu).with_system(button_press_system),
+            );
     }
 }
 
@@ -99,7 +104,7 @@ fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
 }
 
 fn button_press_system(
-    buttons: Query<(&Children, &Interaction), (Changed<Interaction>, With<Button>)>,
+    buttons: Query<(&Children, &Interaction), ChangedButton>,
     mut state: ResMut<State<AppState>>,
     mut image_query: Query<&mut UiImage>,
     ui_assets: Res<UiAssets>,
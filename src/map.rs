@@ -1,10 +1,13 @@
+use crate::collision_layers::CollisionMatrix;
 use crate::common::{EntityType, Position, TextureWrapper};
 use crate::hitbox::Hitbox;
-use crate::move_system::{MoveObjectType, MoveSystemObject};
+use crate::move_system::MoveSystemObject;
 use crate::parser::{MapId, ParsedEntity, Parser};
 use bevy::ecs::event::Events;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use std::collections::HashMap;
+use std::marker::PhantomData;
 
 /*
     (Un)loading and (un)rendering the contents making for a map.
@@ -30,13 +33,19 @@ use std::collections::HashMap;
     Systems to use:
         load_map: read a map from the disc and store it in a hashmap.
         unload_map: remove a map from the hashmap.
-        render_map: display one of the maps currently stored in the hashmap.
+        render_map: display one of the maps, loading it first if it is not in the hashmap.
         unrender_map: make a map disappear.
 
-    All these functions rely on IDs of the maps.
+    All these functions rely on IDs of the maps. Every rendered entity
+    has a MapMarker with the ID of the map it belongs to, so a single map
+    can be unrendered while the others stay on the screen.
 
 */
 
+// Collision layers of the entities of maps.
+pub const OBSTACLE_LAYER: &str = "Obstacle";
+pub const FLOOR_LAYER: &str = "Floor";
+
 pub struct MapPlugin;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
@@ -50,10 +59,14 @@ enum MapLabel {
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(MapStorage::new())
+            .insert_resource(SpawnPoints::default())
+            .insert_resource(RenderedMaps::default())
             .insert_resource(Events::<LoadMap>::default())
             .insert_resource(Events::<UnloadMap>::default())
             .insert_resource(Events::<RenderMap>::default())
             .insert_resource(Events::<UnrenderMap>::default())
+            .add_event::<MapRendered>()
+            .add_event::<MapUnrendered>()
             .add_system(unrender_map.label(MapLabel::Unrender))
             .add_system(unload_map.label(MapLabel::Unload).after(MapLabel::Unrender))
             .add_system(load_map.label(MapLabel::Load).after(MapLabel::Unload))
@@ -64,9 +77,14 @@ impl Plugin for MapPlugin {
 type VecIter<'a, T> = std::slice::Iter<'a, T>;
 type HashMapIter<'a, K, V> = std::collections::hash_map::Iter<'a, K, V>;
 
+/// Marks entities being part of the rendered map of a given ID.
+#[derive(Component, Clone, Copy)]
+pub struct MapMarker(pub MapId);
+
 /// An entity being part of a map.
 #[derive(Bundle, Clone)]
 struct MapEntity {
+    marker: MapMarker,
     #[bundle]
     move_system: MoveSystemObject,
     #[bundle]
@@ -74,9 +92,18 @@ struct MapEntity {
 }
 
 impl MapEntity {
-    fn new(parsed_entity: ParsedEntity, texture: &Handle<Image>) -> MapEntity {
+    fn new(
+        id: MapId,
+        parsed_entity: ParsedEntity,
+        texture: &Handle<Image>,
+        collision_matrix: &CollisionMatrix,
+    ) -> MapEntity {
         MapEntity {
-            move_system: MoveSystemObject::new(parsed_entity.move_type, parsed_entity.hitbox),
+            marker: MapMarker(id),
+            move_system: MoveSystemObject::new(
+                collision_matrix.layers(&parsed_entity.move_type),
+                parsed_entity.hitbox,
+            ),
             sprite_bundle: Self::get_sprite_bundle(&parsed_entity, texture),
         }
     }
@@ -85,10 +112,7 @@ impl MapEntity {
     fn get_sprite_bundle(parsed_entity: &ParsedEntity, texture: &Handle<Image>) -> SpriteBundle {
         SpriteBundle {
             sprite: Sprite {
-                custom_size: match parsed_entity.hitbox {
-                    Hitbox::Rectangle(v) => Some(v), // Vec2 implements the Copy trait.
-                    Hitbox::Circle(_) => panic!(),
-                },
+                custom_size: Some(parsed_entity.hitbox.size()),
                 ..Default::default()
             },
             texture: texture.clone(),
@@ -101,10 +125,10 @@ impl MapEntity {
     }
 
     /// Placeholder for the time being.
-    fn get_color(object_type: MoveObjectType) -> Color {
-        match object_type {
-            MoveObjectType::Obstacle => Color::rgb(0.7, 0.2, 0.5),
-            MoveObjectType::Floor => Color::rgb(0.3, 0.1, 0.7),
+    fn get_color(layer: &str) -> Color {
+        match layer {
+            OBSTACLE_LAYER => Color::rgb(0.7, 0.2, 0.5),
+            FLOOR_LAYER => Color::rgb(0.3, 0.1, 0.7),
             _ => panic!(),
         }
     }
@@ -114,11 +138,15 @@ impl MapEntity {
 #[derive(Clone)]
 struct Map {
     entities: Vec<MapEntity>,
+    spawn_points: Vec<Vec2>,
 }
 
 impl Map {
     fn new() -> Map {
-        Map { entities: vec![] }
+        Map {
+            entities: vec![],
+            spawn_points: vec![],
+        }
     }
 
     fn insert(&mut self, map_entity: MapEntity) {
@@ -131,7 +159,7 @@ impl Map {
 }
 
 /// Structure storing loaded maps.
-struct MapStorage {
+pub struct MapStorage {
     maps: HashMap<MapId, Map>,
 }
 
@@ -161,6 +189,11 @@ impl MapStorage {
     fn iter(&self) -> HashMapIter<'_, MapId, Map> {
         self.maps.iter()
     }
+
+    /// Forgets all loaded maps, e.g. when the game ends.
+    pub fn clear(&mut self) {
+        self.maps.clear();
+    }
 }
 
 /// Message for asking to load the map of a given ID.
@@ -169,11 +202,37 @@ pub struct LoadMap(pub MapId);
 pub struct UnloadMap(pub MapId);
 /// Message for asking to render the map of a given ID.
 pub struct RenderMap(pub MapId);
-/// Message for asking to unrender the map.
-pub struct UnrenderMap;
+/// Message for asking to unrender the map of a given ID.
+pub struct UnrenderMap(pub MapId);
+/// Message sent after the map of a given ID has been rendered.
+/// Its entities exist from the next stage on.
+pub struct MapRendered(pub MapId);
+/// Message sent after the map of a given ID has been unrendered.
+/// Its entities are gone from the next stage on.
+pub struct MapUnrendered(pub MapId);
+
+/// Points of the most recently rendered map where enemies should be spawned,
+/// empty if the map does not define any (or no map is rendered).
+#[derive(Default)]
+pub struct SpawnPoints(pub Vec<Vec2>);
+
+/// IDs of the rendered maps, from the earliest rendered one.
+#[derive(Default)]
+pub struct RenderedMaps(Vec<MapId>);
+
+impl RenderedMaps {
+    pub fn contains(&self, id: MapId) -> bool {
+        self.0.contains(&id)
+    }
 
+    pub fn iter(&self) -> VecIter<'_, MapId> {
+        self.0.iter()
+    }
+}
+
+// Entities of other layers than those of maps have no texture.
 fn get_texture<'a>(
-    object_type: MoveObjectType,
+    layer: &str,
     textures: &'a Res<Vec<TextureWrapper>>,
 ) -> std::option::Option<&'a Handle<Image>> {
     let find_texture = |entity_type: EntityType| {
@@ -183,10 +242,10 @@ fn get_texture<'a>(
             .map(|texture| &texture.texture)
     };
 
-    match object_type {
-        MoveObjectType::Obstacle => find_texture(EntityType::Wall),
-        MoveObjectType::Floor => find_texture(EntityType::Floor),
-        _ => panic!(),
+    match layer {
+        OBSTACLE_LAYER => find_texture(EntityType::Wall),
+        FLOOR_LAYER => find_texture(EntityType::Floor),
+        _ => None,
     }
 }
 
@@ -195,21 +254,37 @@ fn get_texture<'a>(
 ///     If it fails, returns None.
 ///
 /// Arguments:
-///     id : id of the map to be loaded.
+///     id               : id of the map to be loaded,
+///     textures         : textures of the entities of the map,
+///     collision_matrix : collision layers of the entities of the map.
 ///
 /// Return:
 ///     The map of the given ID.
 ///
 /// Maintenance notes:
 ///     None
-fn fetch_map(id: MapId, textures: &Res<Vec<TextureWrapper>>) -> Option<Map> {
+fn fetch_map(
+    id: MapId,
+    textures: &Res<Vec<TextureWrapper>>,
+    collision_matrix: &CollisionMatrix,
+) -> Option<Map> {
     if let Some(parser) = Parser::new(id) {
         let mut map = Map::new();
         for parsed_entity in parser.iter() {
-            if let Some(texture) = get_texture(parsed_entity.move_type, textures) {
-                map.insert(MapEntity::new((*parsed_entity).clone(), texture));
+            if let Some(texture) = get_texture(&parsed_entity.move_type, textures) {
+                map.insert(MapEntity::new(
+                    id,
+                    (*parsed_entity).clone(),
+                    texture,
+                    collision_matrix,
+                ));
             }
         }
+        map.spawn_points = parser
+            .spawn_points()
+            .iter()
+            .map(|&Position(x, y)| Vec2::new(x, y))
+            .collect();
         Some(map)
     } else {
         eprintln!("[fetch_map] There is no map of id {}.", id);
@@ -217,6 +292,21 @@ fn fetch_map(id: MapId, textures: &Res<Vec<TextureWrapper>>) -> Option<Map> {
     }
 }
 
+/// Everything needed to fetch maps from the drive.
+#[derive(SystemParam)]
+struct MapFetcher<'w, 's> {
+    textures: Res<'w, Vec<TextureWrapper>>,
+    collision_matrix: Res<'w, CollisionMatrix>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> MapFetcher<'w, 's> {
+    fn fetch(&self, id: MapId) -> Option<Map> {
+        fetch_map(id, &self.textures, &self.collision_matrix)
+    }
+}
+
 /// Description:
 ///     Loads maps from the disc and stores them in
 ///     the map storage for the future.
@@ -226,21 +316,18 @@ fn fetch_map(id: MapId, textures: &Res<Vec<TextureWrapper>>) -> Option<Map> {
 ///    
 /// Arguments:
 ///     map_ids : IDs of the maps to be loaded,
-///     maps    : the map storage.
+///     maps    : the map storage,
+///     fetcher : textures and collision layers of the entities of the maps.
 ///    
 /// Return:
 ///     None
 ///    
 /// Maintenance notes:
 ///     Loading the maps can be changed to be happening concurrently.
-fn load_map(
-    mut map_ids: EventReader<LoadMap>,
-    mut maps: ResMut<MapStorage>,
-    textures: Res<Vec<TextureWrapper>>,
-) {
+fn load_map(mut map_ids: EventReader<LoadMap>, mut maps: ResMut<MapStorage>, fetcher: MapFetcher) {
     for LoadMap(id) in map_ids.iter() {
         if !maps.contains(*id) {
-            if let Some(map) = fetch_map(*id, &textures) {
+            if let Some(map) = fetcher.fetch(*id) {
                 maps.insert(*id, map);
             } else {
                 eprintln!(
@@ -279,15 +366,20 @@ fn unload_map(mut map_ids: EventReader<UnloadMap>, mut maps: ResMut<MapStorage>)
 
 /// Description:
 ///     Renders maps of given IDs. If a map has not been loaded,
-///     it first loads it.
+///     it first loads it. A map that is already rendered
+///     is not rendered for the second time.
 ///     To render a map, put a tuple struct RenderMap
 ///     with the ID of the map you want to render
 ///     into the EventWriter<RenderMap>.
 ///
 /// Arguments:
-///     commands : commands (for rendering purposes),
-///     map_ids  : IDs of the maps to be rendered,
-///     maps     : the map storage.
+///     commands      : commands (for rendering purposes),
+///     map_ids       : IDs of the maps to be rendered,
+///     maps          : the map storage,
+///     fetcher       : for fetching the maps which have not been loaded,
+///     rendered_maps : IDs of the rendered maps,
+///     spawn_points  : spawn points of the rendered map,
+///     rendered      : notifications about rendered maps.
 ///
 /// Return:
 ///     None
@@ -298,50 +390,92 @@ fn render_map(
     mut commands: Commands,
     mut map_ids: EventReader<RenderMap>,
     mut maps: ResMut<MapStorage>,
+    fetcher: MapFetcher,
+    mut rendered_maps: ResMut<RenderedMaps>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    mut rendered: EventWriter<MapRendered>,
 ) {
-    let mut render = |map: &Map| {
-        for map_entity in map.iter() {
-            commands.spawn_bundle(map_entity.clone());
-        }
-    };
-
     for RenderMap(id) in map_ids.iter() {
-        if let Some(map) = maps.get(*id) {
-            render(map);
-        } else {
+        if rendered_maps.contains(*id) {
+            eprintln!(
+                "[render_map] The map of id {} has already been rendered.",
+                id
+            );
+            continue;
+        }
+        if !maps.contains(*id) {
             eprintln!(
                 "[render_map] The map of id {} has not been loaded. Fetching the map...",
                 id
             );
+            match fetcher.fetch(*id) {
+                Some(map) => maps.insert(*id, map),
+                None => {
+                    eprintln!("[render_map] Fetching the map of id {} has failed.", id);
+                    continue;
+                }
+            }
+        }
+        if let Some(map) = maps.get(*id) {
+            for map_entity in map.iter() {
+                commands.spawn_bundle(map_entity.clone());
+            }
+            spawn_points.0 = map.spawn_points.clone();
+            rendered_maps.0.push(*id);
+            rendered.send(MapRendered(*id));
         }
     }
 }
 
 /// Description:
-///     Loads maps from the disc and stores them in
-///     the map storage for the future.
-///     To unrender the currently displayed map,
-///     put a struct UnrenderMap into
-///     the EventWriter<UnrenderMap>.
+///     Despawns the entities of maps of given IDs.
+///     The maps stay in the map storage.
+///     To unrender a map, put a tuple struct UnrenderMap
+///     with the ID of the map you want to unrender
+///     into the EventWriter<UnrenderMap>.
 ///
 /// Arguments:
-///     commands : commands (for despawning purposes)
-///     request  : just a label to know that the system should unrender the map,
-///     entities : query storing entities to be removed.
+///     commands      : commands (for despawning purposes),
+///     map_ids       : IDs of the maps to be unrendered,
+///     entities      : entities of all rendered maps,
+///     maps          : the map storage,
+///     rendered_maps : IDs of the rendered maps,
+///     spawn_points  : spawn points of the rendered map,
+///     unrendered    : notifications about unrendered maps.
 ///
 /// Return:
 ///     None
 ///
 /// Maintenance notes:
-///     None
+///     If the map providing the spawn points is unrendered,
+///     they are taken from the most recently rendered one left
+///     (if it is still in the map storage).
 fn unrender_map(
     mut commands: Commands,
-    mut request: EventReader<UnrenderMap>,
-    entities: Query<Entity, With<Position>>,
+    mut map_ids: EventReader<UnrenderMap>,
+    entities: Query<(Entity, &MapMarker)>,
+    maps: Res<MapStorage>,
+    mut rendered_maps: ResMut<RenderedMaps>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    mut unrendered: EventWriter<MapUnrendered>,
 ) {
-    if request.iter().next().is_some() {
-        for entity in entities.iter() {
-            commands.entity(entity).despawn();
+    for UnrenderMap(id) in map_ids.iter() {
+        if !rendered_maps.contains(*id) {
+            eprintln!("[unrender_map] The map of id {} is not rendered.", id);
+            continue;
+        }
+        for (entity, MapMarker(owner)) in entities.iter() {
+            if owner == id {
+                commands.entity(entity).despawn();
+            }
         }
+        rendered_maps.0.retain(|rendered| rendered != id);
+        spawn_points.0 = rendered_maps
+            .0
+            .last()
+            .and_then(|&last| maps.get(last))
+            .map(|map| map.spawn_points.clone())
+            .unwrap_or_default();
+        unrendered.send(MapUnrendered(*id));
     }
 }
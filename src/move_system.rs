@@ -1,31 +1,48 @@
-use crate::move_system::MoveObjectType::*;
 use bevy::prelude::*;
-use serde::{Deserialize, Serialize};
 use std::ops::Add;
 
+use crate::collision_layers::{CollisionLayers, CollisionMatrix};
 use crate::hitbox::Hitbox;
 use crate::player::PlayerMarker;
+use crate::simulation::{SimulationAppExt, SIMULATION_TICK};
+use crate::spatial_hash::SpatialHash;
 
 /*
     This system moves object accordingly to value of their VelocityVector, this vector has to be
-    set by other system every frame, because it is cleared (set to ZERO) at the beginning of event
-    loop iteration. Systems that modify VelocityVector should have label ModifyVelocity, this will
+    set by other system every simulation tick, because it is cleared (set to ZERO) at the beginning
+    of every tick (description in file simulation.rs). Systems that modify VelocityVector should have label ModifyVelocity, this will
     ensure that the execute after clearing vector and before moving objects.
 
-    When move_system detects collision of two objects it sends event of type CollisionEvent
+    When move_system detects collision of two objects it sends event of type CollisionEvent.
+    Objects with ContinuousCollision are checked along their whole path, not only at its end,
+    and stop at the first object on that path that blocks them.
+    Which objects collide with each other is decided by their CollisionLayers
+    (description in file collision_layers.rs), which also tell in CollisionEvents
+    what kind of objects collided.
+    Objects that would end up overlapping something are moved along each axis separately
+    as far as possible, so they slide along walls instead of stopping completely.
+
+    To avoid testing every pair of objects, move_system puts them into a SpatialHash first
+    and only tests pairs of objects that are close to each other.
 */
 
+// Size of a broad phase cell, a bit bigger than the map tiles.
+const BROAD_PHASE_CELL_SIZE: f32 = 64.;
+// Upper bound on the number of steps of a continuous collision check.
+const MAX_SWEEP_STEPS: usize = 64;
+
 pub struct MoveSystemPlugin;
 
 impl Plugin for MoveSystemPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<CollisionEvent>()
-            .add_system(
+            .insert_resource(CollisionMatrix::load())
+            .add_simulation_system(
                 clear_velocity_vector
                     .label(ClearVelocity)
                     .before(ModifyVelocity),
             )
-            .add_system(
+            .add_simulation_system(
                 move_system
                     .after(ModifyVelocity)
                     .after(ClearVelocity)
@@ -45,9 +62,14 @@ pub struct HandleCollisionEvents;
 
 pub struct CollisionEvent {
     pub object_id: Entity,
-    pub object_type: MoveObjectType,
+    pub object_layers: CollisionLayers,
     pub collided_with_id: Entity,
-    pub collided_with_type: MoveObjectType,
+    pub collided_with_layers: CollisionLayers,
+    // Unit vector pointing from the object towards the one it collided with.
+    pub normal: Vec2,
+    // How deep the objects would overlap after the move.
+    pub depth: f32,
+    pub contact_point: Vec2,
 }
 
 // Add to moving objects and static obstacles.
@@ -58,27 +80,24 @@ pub struct MoveSystemMarker;
 #[derive(Component, Copy, Clone)]
 pub struct VelocityVector(pub Vec2);
 
-#[derive(Component, Copy, Clone, PartialEq, Serialize, Deserialize)]
-pub enum MoveObjectType {
-    Obstacle,
-    Floor,
-    Player,
-    Enemy,
-    PlayerBullet,
-}
+// Add to fast moving objects (e.g. bullets), so that they cannot pass through
+// other objects between frames. Their move is checked in small steps and they
+// stop at the first object blocking them.
+#[derive(Component, Copy, Clone)]
+pub struct ContinuousCollision;
 
 #[derive(Bundle, Copy, Clone)]
 pub struct MoveSystemObject {
     marker: MoveSystemMarker,
-    object_type: MoveObjectType,
+    layers: CollisionLayers,
     hitbox: Hitbox,
 }
 
 impl MoveSystemObject {
-    pub fn new(object_type: MoveObjectType, hitbox: Hitbox) -> MoveSystemObject {
+    pub fn new(layers: CollisionLayers, hitbox: Hitbox) -> MoveSystemObject {
         MoveSystemObject {
             marker: MoveSystemMarker,
-            object_type,
+            layers,
             hitbox,
         }
     }
@@ -92,22 +111,15 @@ pub struct MoveSystemObjectWithVelocity {
 }
 
 impl MoveSystemObjectWithVelocity {
-    pub fn new(
-        object_type: MoveObjectType,
-        hitbox: Hitbox,
-        vel: Vec2,
-    ) -> MoveSystemObjectWithVelocity {
+    pub fn new(layers: CollisionLayers, hitbox: Hitbox, vel: Vec2) -> MoveSystemObjectWithVelocity {
         MoveSystemObjectWithVelocity {
-            object_bundle: MoveSystemObject::new(object_type, hitbox),
+            object_bundle: MoveSystemObject::new(layers, hitbox),
             velocity: VelocityVector(vel),
         }
     }
 
-    pub fn new_with_vel_0(
-        object_type: MoveObjectType,
-        hitbox: Hitbox,
-    ) -> MoveSystemObjectWithVelocity {
-        MoveSystemObjectWithVelocity::new(object_type, hitbox, Vec2::new(0., 0.))
+    pub fn new_with_vel_0(layers: CollisionLayers, hitbox: Hitbox) -> MoveSystemObjectWithVelocity {
+        MoveSystemObjectWithVelocity::new(layers, hitbox, Vec2::new(0., 0.))
     }
 }
 
@@ -118,55 +130,37 @@ fn clear_velocity_vector(mut vector_query: Query<(&mut VelocityVector), (With<Mo
     }
 }
 
-// Completely ignore collision - collision has no effect on moving objects
-// and information about it is not passed to other systems.
-fn ignore_collision(type_1: &MoveObjectType, type_2: &MoveObjectType) -> bool {
-    match (type_1, type_2) {
-        (PlayerBullet, PlayerBullet) => false,
-        (Player, PlayerBullet) | (PlayerBullet, Player) => true,
-        (Player, Floor) | (Floor, Player) => true,
-        (Enemy, Floor) | (Floor, Enemy) => true,
-        (PlayerBullet, Floor) | (Floor, PlayerBullet) => true,
-        (Enemy, Enemy) => true,
-        _ => false,
-    }
-}
-
-// Send information about collision to other systems, but ignore its effect on moving objects.
-fn allow_overlap(type_1: &MoveObjectType, type_2: &MoveObjectType) -> bool {
-    match (type_1, type_2) {
-        _ => false,
-    }
-}
+type MoveQueryItem<'a> = (
+    &'a mut Transform,
+    &'a Hitbox,
+    &'a CollisionLayers,
+    Option<&'a VelocityVector>,
+    Entity,
+    Option<&'a ContinuousCollision>,
+);
 
 // Try to move objects accordingly to their velocity vectors,
 // after other systems modified those vectors.
 fn move_system(
-    time: Res<Time>,
-    mut to_move_query: Query<
-        (
-            &mut Transform,
-            &Hitbox,
-            &MoveObjectType,
-            Option<&VelocityVector>,
-            Entity,
-        ),
-        (With<MoveSystemMarker>),
-    >,
+    collision_matrix: Res<CollisionMatrix>,
+    mut to_move_query: Query<MoveQueryItem, With<MoveSystemMarker>>,
     mut collision_writer: EventWriter<CollisionEvent>,
 ) {
-    let delta_time = time.delta().as_secs_f32();
-    let to_move_iterator = to_move_query
-        .iter_mut()
-        .filter(|(_, __, &x, _, _)| x != MoveObjectType::Floor);
+    let delta_time = SIMULATION_TICK;
     let mut to_move_vec = vec![];
-    for entry in to_move_iterator {
-        to_move_vec.push(entry);
+    let mut layers = vec![];
+    for entry in to_move_query.iter_mut() {
+        if !collision_matrix.is_inert(entry.2) {
+            layers.push(*entry.2);
+            to_move_vec.push(entry);
+        }
     }
     let mut no_collision = vec![true; to_move_vec.len()];
+    let mut current_position = vec![];
     let mut future_position = vec![];
     for v in to_move_vec.iter() {
         let mut pos = v.0.translation.truncate();
+        current_position.push(pos);
         let vel_option = v.3;
         match vel_option {
             None => (),
@@ -174,43 +168,133 @@ fn move_system(
         }
         future_position.push(pos);
     }
+
+    // Broad phase - only objects close to each other are tested for collision.
+    // Objects are inserted with the whole area they may occupy during this move.
+    let mut grid = SpatialHash::new(BROAD_PHASE_CELL_SIZE);
+    let mut swept_box = vec![];
+    for (i, v) in to_move_vec.iter().enumerate() {
+        let half_extents = v.1.half_extents();
+        let min = current_position[i].min(future_position[i]) - half_extents;
+        let max = current_position[i].max(future_position[i]) + half_extents;
+        grid.insert(i, min, max);
+        swept_box.push((min, max));
+    }
+
+    // Objects with continuous collision are moved only up to the first point
+    // of their path where something blocks them, so that the checks below
+    // report the earliest impact. Contacts that are only reported are left to those checks.
     for i in 0..to_move_vec.len() {
-        for j in (i + 1)..to_move_vec.len() {
-            let (_, first_hitbox, first_type, _, first_id) = to_move_vec[i];
-            let (_, second_hitbox, second_type, _, second_id) = to_move_vec[j];
-            if ignore_collision(first_type, second_type) {
-                continue;
+        if to_move_vec[i].5.is_none() {
+            continue;
+        }
+        let hitbox = to_move_vec[i].1;
+        let delta = future_position[i] - current_position[i];
+        let half_extents = hitbox.half_extents();
+        let step_length = half_extents.x.min(half_extents.y);
+        if step_length <= 0. {
+            continue;
+        }
+        let steps = ((delta.length() / step_length).ceil() as usize).clamp(1, MAX_SWEEP_STEPS);
+        let (min, max) = swept_box[i];
+        let neighbours = grid.query(min, max);
+        for step in 1..=steps {
+            let position = current_position[i] + delta * (step as f32 / steps as f32);
+            let hit = neighbours.iter().any(|&j| {
+                j != i
+                    && layers[i].is_blocked_by(&layers[j])
+                    && Hitbox::check_collision(
+                        hitbox,
+                        position,
+                        to_move_vec[j].1,
+                        future_position[j],
+                    )
+            });
+            if hit {
+                future_position[i] = position;
+                break;
             }
-            if Hitbox::check_collision(
-                first_hitbox,
-                future_position[i],
-                second_hitbox,
-                future_position[j],
-            ) {
-                if !allow_overlap(first_type, second_type) {
-                    no_collision[i] = false;
-                    no_collision[j] = false;
-                }
-
-                collision_writer.send(CollisionEvent {
-                    object_id: first_id,
-                    object_type: *first_type,
-                    collided_with_id: second_id,
-                    collided_with_type: *second_type,
-                });
-                collision_writer.send(CollisionEvent {
-                    object_id: second_id,
-                    object_type: *second_type,
-                    collided_with_id: first_id,
-                    collided_with_type: *first_type,
-                });
+        }
+    }
+
+    for (i, j) in grid.pairs() {
+        let (_, first_hitbox, _, _, first_id, _) = to_move_vec[i];
+        let (_, second_hitbox, _, _, second_id, _) = to_move_vec[j];
+        if !layers[i].interacts_with(&layers[j]) {
+            continue;
+        }
+        if let Some(contact) = Hitbox::contact(
+            first_hitbox,
+            future_position[i],
+            second_hitbox,
+            future_position[j],
+        ) {
+            if layers[i].is_blocked_by(&layers[j]) {
+                no_collision[i] = false;
+            }
+            if layers[j].is_blocked_by(&layers[i]) {
+                no_collision[j] = false;
             }
+
+            collision_writer.send(CollisionEvent {
+                object_id: first_id,
+                object_layers: layers[i],
+                collided_with_id: second_id,
+                collided_with_layers: layers[j],
+                normal: contact.normal,
+                depth: contact.depth,
+                contact_point: contact.point,
+            });
+            collision_writer.send(CollisionEvent {
+                object_id: second_id,
+                object_layers: layers[j],
+                collided_with_id: first_id,
+                collided_with_layers: layers[i],
+                normal: -contact.normal,
+                depth: contact.depth,
+                contact_point: contact.point,
+            });
         }
     }
+
+    // Objects that did not collide take their future position. The rest try to move
+    // along each axis separately, so that they slide along obstacles instead of stopping.
+    let mut final_position: Vec<Vec2> = (0..to_move_vec.len())
+        .map(|i| {
+            if no_collision[i] {
+                future_position[i]
+            } else {
+                current_position[i]
+            }
+        })
+        .collect();
     for i in 0..to_move_vec.len() {
         if no_collision[i] {
-            to_move_vec[i].0.translation =
-                future_position[i].extend(to_move_vec[i].0.translation.z);
+            continue;
         }
+        let (min, max) = swept_box[i];
+        let neighbours = grid.query(min, max);
+        let is_blocked_at = |position: Vec2, final_position: &[Vec2]| {
+            let hitbox = to_move_vec[i].1;
+            neighbours.iter().any(|&j| {
+                let other_hitbox = to_move_vec[j].1;
+                j != i
+                    && layers[i].is_blocked_by(&layers[j])
+                    && Hitbox::check_collision(hitbox, position, other_hitbox, final_position[j])
+            })
+        };
+
+        let delta = future_position[i] - current_position[i];
+        let mut position = current_position[i];
+        for step in [Vec2::new(delta.x, 0.), Vec2::new(0., delta.y)] {
+            if step != Vec2::ZERO && !is_blocked_at(position + step, &final_position) {
+                position += step;
+            }
+        }
+        final_position[i] = position;
+    }
+
+    for (i, position) in final_position.into_iter().enumerate() {
+        to_move_vec[i].0.translation = position.extend(to_move_vec[i].0.translation.z);
     }
 }
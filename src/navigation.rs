@@ -0,0 +1,483 @@
+use bevy::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::collision_layers::{CollisionLayers, CollisionMatrix};
+use crate::hitbox::Hitbox;
+use crate::map::{MapRendered, MapUnrendered, FLOOR_LAYER, OBSTACLE_LAYER};
+use crate::player::PlayerMarker;
+use crate::simulation::{run_in_state, SimulationAppExt};
+use crate::AppState;
+
+/*
+    Navigation of enemies around the obstacles of the map.
+
+    NavGrid divides the area covered by the floor and the obstacles of the currently
+    rendered map into square cells and remembers which of them can be walked on,
+    i.e. are covered by a floor tile and not blocked by an obstacle.
+    It is rebuilt every time a map is rendered or unrendered.
+
+    NavGrid::find_path looks for the shortest path between two points
+    using A* (moving diagonally is allowed, but cutting corners is not).
+
+    For many enemies chasing the player, one A* search per enemy is too expensive.
+    FlowField stores the distance from every walkable cell to the player instead,
+    so every enemy only has to step to the neighbouring cell closest to the player.
+    It is computed once for all enemies and updated only when the player
+    moves to another cell (reusing the previous distances, see FlowField::retarget)
+    or from scratch when the grid is rebuilt.
+*/
+
+// The same as the size of map tiles, so the cells match the tiles.
+pub const NAV_CELL_SIZE: f32 = 30.;
+
+// Costs of moving between neighbouring cells (diagonal ~ straight * sqrt(2)).
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+pub type GridCell = (i32, i32);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct UpdateFlowField;
+
+pub struct NavigationPlugin;
+
+impl Plugin for NavigationPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NavGrid::empty())
+            .insert_resource(FlowField::empty())
+            // After the commands of the map systems have been applied.
+            .add_system_to_stage(CoreStage::PostUpdate, rebuild_nav_grid)
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    .with_system(update_flow_field.label(UpdateFlowField)),
+            );
+    }
+}
+
+pub struct NavGrid {
+    // Corner of the cell (0, 0) with the smallest coordinates.
+    origin: Vec2,
+    width: i32,
+    height: i32,
+    blocked: Vec<bool>,
+    // Increased on every rebuild, so paths computed on an old grid can be detected.
+    generation: u32,
+}
+
+impl NavGrid {
+    pub fn empty() -> NavGrid {
+        NavGrid {
+            origin: Vec2::ZERO,
+            width: 0,
+            height: 0,
+            blocked: vec![],
+            generation: 0,
+        }
+    }
+
+    // Cells covered by the box, without those touching it only by their sides.
+    fn cells_of(&self, (box_min, box_max): (Vec2, Vec2)) -> impl Iterator<Item = GridCell> {
+        let first = ((box_min - self.origin) / NAV_CELL_SIZE + 0.01).floor();
+        let last = ((box_max - self.origin) / NAV_CELL_SIZE - 0.01).ceil();
+        ((first.x as i32)..(last.x as i32))
+            .flat_map(move |x| ((first.y as i32)..(last.y as i32)).map(move |y| (x, y)))
+    }
+
+    // Without any floor tiles, every cell not blocked by an obstacle is walkable.
+    fn build<'a>(
+        obstacles: impl Iterator<Item = (Vec2, &'a Hitbox)>,
+        floors: impl Iterator<Item = (Vec2, &'a Hitbox)>,
+        generation: u32,
+    ) -> NavGrid {
+        let to_box = |(position, hitbox): (Vec2, &Hitbox)| {
+            let half_extents = hitbox.half_extents();
+            (position - half_extents, position + half_extents)
+        };
+        let obstacles: Vec<(Vec2, Vec2)> = obstacles.map(to_box).collect();
+        let floors: Vec<(Vec2, Vec2)> = floors.map(to_box).collect();
+        if obstacles.is_empty() && floors.is_empty() {
+            return NavGrid {
+                generation,
+                ..NavGrid::empty()
+            };
+        }
+
+        let min = obstacles
+            .iter()
+            .chain(floors.iter())
+            .fold(Vec2::splat(f32::INFINITY), |acc, b| acc.min(b.0));
+        let max = obstacles
+            .iter()
+            .chain(floors.iter())
+            .fold(Vec2::splat(f32::NEG_INFINITY), |acc, b| acc.max(b.1));
+        let size = ((max - min) / NAV_CELL_SIZE).ceil();
+        let mut grid = NavGrid {
+            origin: min,
+            width: size.x as i32,
+            height: size.y as i32,
+            blocked: vec![!floors.is_empty(); (size.x * size.y) as usize],
+            generation,
+        };
+
+        // Only the floor of the room can be walked on...
+        for floor in floors {
+            for cell in grid.cells_of(floor) {
+                if let Some(index) = grid.index_of(cell) {
+                    grid.blocked[index] = false;
+                }
+            }
+        }
+        // ...unless there is an obstacle on it.
+        for obstacle in obstacles {
+            for cell in grid.cells_of(obstacle) {
+                if let Some(index) = grid.index_of(cell) {
+                    grid.blocked[index] = true;
+                }
+            }
+        }
+        grid
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn index_of(&self, (x, y): GridCell) -> Option<usize> {
+        (x >= 0 && y >= 0 && x < self.width && y < self.height)
+            .then(|| (y * self.width + x) as usize)
+    }
+
+    pub fn cell_of(&self, position: Vec2) -> Option<GridCell> {
+        let cell = ((position - self.origin) / NAV_CELL_SIZE).floor();
+        let cell = (cell.x as i32, cell.y as i32);
+        self.index_of(cell).map(|_| cell)
+    }
+
+    pub fn center_of(&self, (x, y): GridCell) -> Vec2 {
+        self.origin + (Vec2::new(x as f32, y as f32) + 0.5) * NAV_CELL_SIZE
+    }
+
+    // Corners of the area covered by the grid, i.e. of the rendered room.
+    pub fn bounds(&self) -> Option<(Vec2, Vec2)> {
+        (self.width > 0 && self.height > 0).then(|| {
+            let size = Vec2::new(self.width as f32, self.height as f32) * NAV_CELL_SIZE;
+            (self.origin, self.origin + size)
+        })
+    }
+
+    pub fn is_walkable(&self, cell: GridCell) -> bool {
+        self.index_of(cell)
+            .is_some_and(|index| !self.blocked[index])
+    }
+
+    // Whether all cells within the given number of cells from the cell are walkable.
+    pub fn is_clear(&self, (x, y): GridCell, radius: i32) -> bool {
+        (-radius..=radius).all(|dx| (-radius..=radius).all(|dy| self.is_walkable((x + dx, y + dy))))
+    }
+
+    pub fn walkable_cells(&self) -> impl Iterator<Item = GridCell> + '_ {
+        (0..self.height)
+            .flat_map(move |y| (0..self.width).map(move |x| (x, y)))
+            .filter(|&cell| self.is_walkable(cell))
+    }
+
+    // Walkable neighbours of the cell with costs of moving to them.
+    pub fn neighbours(&self, (x, y): GridCell) -> impl Iterator<Item = (GridCell, u32)> + '_ {
+        [
+            (1, 0),
+            (-1, 0),
+            (0, 1),
+            (0, -1),
+            (1, 1),
+            (1, -1),
+            (-1, 1),
+            (-1, -1),
+        ]
+        .into_iter()
+        .filter(move |&(dx, dy)| {
+            // Moving diagonally requires both adjacent cells to be free.
+            self.is_walkable((x + dx, y + dy))
+                && self.is_walkable((x + dx, y))
+                && self.is_walkable((x, y + dy))
+        })
+        .map(move |(dx, dy)| {
+            let cost = if dx != 0 && dy != 0 {
+                DIAGONAL_COST
+            } else {
+                STRAIGHT_COST
+            };
+            ((x + dx, y + dy), cost)
+        })
+    }
+
+    // Octile distance - the cost of the shortest path if there were no obstacles.
+    fn estimate((x_1, y_1): GridCell, (x_2, y_2): GridCell) -> u32 {
+        let dx = (x_1 - x_2).unsigned_abs();
+        let dy = (y_1 - y_2).unsigned_abs();
+        STRAIGHT_COST * dx.max(dy) + (DIAGONAL_COST - STRAIGHT_COST) * dx.min(dy)
+    }
+
+    // Returns points to visit one by one to get from one position to the other,
+    // the last one being the destination itself. Returns None if there is no path
+    // or one of the positions lies outside of the grid.
+    pub fn find_path(&self, from: Vec2, to: Vec2) -> Option<Vec<Vec2>> {
+        let start = self.cell_of(from)?;
+        let goal = self.cell_of(to)?;
+        if !self.is_walkable(goal) {
+            return None;
+        }
+
+        let mut open = BinaryHeap::new();
+        let mut cost_so_far: HashMap<GridCell, u32> = HashMap::new();
+        let mut came_from: HashMap<GridCell, GridCell> = HashMap::new();
+        open.push(Reverse((Self::estimate(start, goal), start)));
+        cost_so_far.insert(start, 0);
+
+        while let Some(Reverse((_, cell))) = open.pop() {
+            if cell == goal {
+                let mut path = vec![to];
+                let mut current = goal;
+                while let Some(&previous) = came_from.get(&current) {
+                    if previous != start {
+                        path.push(self.center_of(previous));
+                    }
+                    current = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let cost = cost_so_far[&cell];
+            for (next, step_cost) in self.neighbours(cell) {
+                let next_cost = cost + step_cost;
+                if cost_so_far.get(&next).is_none_or(|&old| next_cost < old) {
+                    cost_so_far.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Reverse((next_cost + Self::estimate(next, goal), next)));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct FlowField {
+    // Cost of the shortest path from every cell of the grid to the target cell.
+    distances: Vec<u32>,
+    target: Option<GridCell>,
+    grid_generation: u32,
+}
+
+impl FlowField {
+    pub fn empty() -> FlowField {
+        FlowField {
+            distances: vec![],
+            target: None,
+            grid_generation: 0,
+        }
+    }
+
+    // Dijkstra's algorithm started from the target cell.
+    fn compute(&mut self, grid: &NavGrid, target: Option<GridCell>) {
+        self.target = target;
+        self.grid_generation = grid.generation();
+        self.distances.clear();
+        self.distances.resize(grid.blocked.len(), u32::MAX);
+        match target {
+            Some(target) if grid.is_walkable(target) => self.spread_from(grid, target),
+            _ => (),
+        }
+    }
+
+    // Updates the field after the target has moved to another cell of the same grid.
+    // Every cell can still reach the new target through the old one, so the old
+    // distances increased by the distance between the targets are correct or too big.
+    // Only the cells that are closer to the new target than that have to be visited.
+    fn retarget(&mut self, grid: &NavGrid, target: Option<GridCell>) {
+        let shift = match target {
+            Some(cell) if self.grid_generation == grid.generation() && grid.is_walkable(cell) => {
+                self.distance(grid, cell)
+            }
+            _ => u32::MAX,
+        };
+        // The target left the grid, or the field is out of date.
+        if shift == u32::MAX {
+            self.compute(grid, target);
+            return;
+        }
+
+        self.target = target;
+        for distance in self.distances.iter_mut().filter(|d| **d != u32::MAX) {
+            *distance += shift;
+        }
+        self.spread_from(grid, target.unwrap());
+    }
+
+    // Lowers the distances of the cells to which there is a shorter way to the target.
+    fn spread_from(&mut self, grid: &NavGrid, target: GridCell) {
+        let mut open = BinaryHeap::new();
+        self.distances[grid.index_of(target).unwrap()] = 0;
+        open.push(Reverse((0, target)));
+        while let Some(Reverse((distance, cell))) = open.pop() {
+            if distance > self.distances[grid.index_of(cell).unwrap()] {
+                continue;
+            }
+            for (next, cost) in grid.neighbours(cell) {
+                let index = grid.index_of(next).unwrap();
+                if distance + cost < self.distances[index] {
+                    self.distances[index] = distance + cost;
+                    open.push(Reverse((distance + cost, next)));
+                }
+            }
+        }
+    }
+
+    fn distance(&self, grid: &NavGrid, cell: GridCell) -> u32 {
+        grid.index_of(cell)
+            .and_then(|index| self.distances.get(index).copied())
+            .unwrap_or(u32::MAX)
+    }
+
+    // Direction in which an object at the given position should go to reach the target.
+    // Returns None in the target cell, outside of the grid or if the target is unreachable,
+    // in which case it is best to head straight for the target.
+    pub fn direction_at(&self, grid: &NavGrid, position: Vec2) -> Option<Vec2> {
+        let cell = grid.cell_of(position)?;
+        if Some(cell) == self.target || self.distance(grid, cell) == u32::MAX {
+            return None;
+        }
+        let (next, _) = grid
+            .neighbours(cell)
+            .min_by_key(|&(next, _)| self.distance(grid, next))?;
+        Some((grid.center_of(next) - position).normalize_or_zero())
+    }
+}
+
+fn update_flow_field(
+    mut flow_field: ResMut<FlowField>,
+    nav_grid: Res<NavGrid>,
+    player: Query<&Transform, With<PlayerMarker>>,
+) {
+    if let Ok(player_transform) = player.get_single() {
+        let player_cell = nav_grid.cell_of(player_transform.translation.truncate());
+        if player_cell != flow_field.target || flow_field.grid_generation != nav_grid.generation() {
+            flow_field.retarget(&nav_grid, player_cell);
+        }
+    }
+}
+
+fn rebuild_nav_grid(
+    mut rendered: EventReader<MapRendered>,
+    mut unrendered: EventReader<MapUnrendered>,
+    mut nav_grid: ResMut<NavGrid>,
+    map_entities: Query<(&Transform, &Hitbox, &CollisionLayers)>,
+    collision_matrix: Res<CollisionMatrix>,
+) {
+    // Both iterators have to be consumed, so no short-circuiting here.
+    let rendered_count = rendered.iter().count();
+    let unrendered_count = unrendered.iter().count();
+    if rendered_count + unrendered_count == 0 {
+        return;
+    }
+    let of_layer = |layer| {
+        map_entities
+            .iter()
+            .filter(move |(_, _, layers)| layers.is_in(layer))
+            .map(|(transform, hitbox, _)| (transform.translation.truncate(), hitbox))
+    };
+    *nav_grid = NavGrid::build(
+        of_layer(collision_matrix.layer(OBSTACLE_LAYER)),
+        of_layer(collision_matrix.layer(FLOOR_LAYER)),
+        nav_grid.generation + 1,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Grid with one cell per character, '#' being blocked. Row i has y = i.
+    fn grid(rows: &[&str]) -> NavGrid {
+        let blocked: Vec<bool> = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|c| c == '#'))
+            .collect();
+        NavGrid {
+            origin: Vec2::ZERO,
+            width: rows[0].len() as i32,
+            height: rows.len() as i32,
+            blocked,
+            generation: 1,
+        }
+    }
+
+    #[test]
+    fn path_goes_around_a_wall() {
+        let grid = grid(&["..#..", "..#..", "....."]);
+        let from = grid.center_of((0, 0));
+        let to = grid.center_of((4, 0));
+        let path = grid.find_path(from, to).unwrap();
+        assert_eq!(path.last(), Some(&to));
+
+        let cells: Vec<GridCell> = std::iter::once(from)
+            .chain(path)
+            .map(|point| grid.cell_of(point).unwrap())
+            .collect();
+        assert!(cells.contains(&(2, 2)));
+        for pair in cells.windows(2) {
+            assert!(grid.neighbours(pair[0]).any(|(next, _)| next == pair[1]));
+        }
+        // Up the wall, over it (without cutting its corner) and down again.
+        assert_eq!(cells.len(), 7);
+    }
+
+    #[test]
+    fn there_is_no_path_through_walls() {
+        let grid = grid(&["..#..", "..#..", "..#.."]);
+        let from = grid.center_of((0, 1));
+        assert_eq!(grid.find_path(from, grid.center_of((4, 1))), None);
+        assert_eq!(grid.find_path(from, grid.center_of((2, 1))), None);
+        assert_eq!(grid.find_path(from, Vec2::new(-100., 0.)), None);
+        assert!(grid.find_path(from, grid.center_of((1, 2))).is_some());
+    }
+
+    #[test]
+    fn flow_field_leads_to_the_target() {
+        let grid = grid(&["..#..", "..#..", "....."]);
+        let mut field = FlowField::empty();
+        field.compute(&grid, Some((4, 0)));
+        assert_eq!(field.distance(&grid, (4, 0)), 0);
+        assert_eq!(field.distance(&grid, (2, 0)), u32::MAX);
+        // Around the wall: two diagonal and four straight steps.
+        assert_eq!(
+            field.distance(&grid, (0, 0)),
+            2 * DIAGONAL_COST + 4 * STRAIGHT_COST
+        );
+        let direction = field.direction_at(&grid, grid.center_of((1, 1))).unwrap();
+        assert_eq!(direction, Vec2::Y);
+    }
+
+    #[test]
+    fn retargeting_gives_the_same_distances_as_computing_from_scratch() {
+        let grid = grid(&[
+            "......#...",
+            ".####.#.#.",
+            ".#....#.#.",
+            ".#.####.#.",
+            "...#.....#",
+            "##.#.###..",
+            "...#...#..",
+        ]);
+        let mut field = FlowField::empty();
+        field.compute(&grid, Some((0, 0)));
+        let targets = [(1, 0), (2, 0), (2, 2), (9, 6), (4, 4), (5, 0), (0, 6)];
+        for target in targets {
+            field.retarget(&grid, Some(target));
+            let mut expected = FlowField::empty();
+            expected.compute(&grid, Some(target));
+            assert_eq!(field.distances, expected.distances, "target {:?}", target);
+            assert_eq!(field.target, Some(target));
+        }
+    }
+}
@@ -2,7 +2,6 @@ use bevy::prelude::*;
 
 use crate::common::Position;
 use crate::hitbox::Hitbox;
-use crate::move_system::MoveObjectType;
 use std::option::Option;
 
 // File i/o libraries:
@@ -21,13 +20,28 @@ const SAVES_PATH: &str = "saves/map";
 // Structure of objects not rendered yet.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ParsedEntity {
-    pub move_type: MoveObjectType,
+    // Name of the collision layer (description in file collision_layers.rs).
+    pub move_type: String,
     pub position: Position,
     pub hitbox: Hitbox,
 }
 
+// Map files are either just a list of entities, or an object
+// with the entities and points where enemies can be spawned.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum MapFile {
+    Entities(Vec<ParsedEntity>),
+    WithSpawnPoints {
+        entities: Vec<ParsedEntity>,
+        #[serde(default)]
+        spawn_points: Vec<Position>,
+    },
+}
+
 pub struct Parser {
     entities: Vec<ParsedEntity>,
+    spawn_points: Vec<Position>,
 }
 
 // Returns unique string made from given ID (may be made more complicated if needed).
@@ -64,18 +78,32 @@ impl Parser {
         let filename = get_filename(&id);
 
         let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
-        let result: Result<Vec<ParsedEntity>> = serde_json::from_str(&contents);
+        let result: Result<MapFile> = serde_json::from_str(&contents);
 
-        return match result {
-            Ok(val) => Some(Parser { entities: val }),
+        match result {
+            Ok(MapFile::Entities(entities)) => Some(Parser {
+                entities,
+                spawn_points: vec![],
+            }),
+            Ok(MapFile::WithSpawnPoints {
+                entities,
+                spawn_points,
+            }) => Some(Parser {
+                entities,
+                spawn_points,
+            }),
             Err(err) => {
                 println!("Incorrect file content");
                 None
             }
-        };
+        }
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, ParsedEntity> {
         self.entities.iter()
     }
+
+    pub fn spawn_points(&self) -> &[Position] {
+        &self.spawn_points
+    }
 }
@@ -1,29 +1,57 @@
 use bevy::math::{vec2, vec3};
 use bevy::prelude::*;
-use std::time::Duration;
 
+use crate::collision_layers::{CollisionLayers, CollisionMatrix};
 use crate::common::{EntityType, TextureWrapper};
-use crate::enemy::EnemyMarker;
-use crate::health_system::{DeathEvent, HealthData, ModifyHealth, ReadDeaths, TakeDamageEvent};
+use crate::enemy::{EnemyMarker, ENEMY_LAYER};
+use crate::health_system::{
+    ContactDamage, Damage, DamageCooldowns, DeathEvent, HealthData, Invulnerability, ModifyHealth,
+    ReadDeaths, TakeDamageEvent,
+};
 use crate::hitbox::Hitbox;
-use crate::move_system::MoveObjectType::{Enemy, PlayerBullet};
+use crate::items::Inventory;
+use crate::map::OBSTACLE_LAYER;
 use crate::move_system::{
-    CollisionEvent, HandleCollisionEvents, ModifyVelocity, MoveObjectType,
+    CollisionEvent, ContinuousCollision, HandleCollisionEvents, ModifyVelocity,
     MoveSystemObjectWithVelocity, VelocityVector,
 };
-use crate::window::WinSize;
-use crate::{hitbox, player, AppState, Player};
+use crate::navigation::NavGrid;
+use crate::projectile::{
+    bounce, bullet_layers, split_directions, Bouncing, Piercing, ProjectileModifiers, Splitting,
+    SPLIT_SIZE_FACTOR,
+};
+use crate::simulation::{run_in_state, SimulationAppExt, SIMULATION_TICK};
+use crate::{hitbox, player, AppState};
 
+// Collision layers of the player and its bullets.
+pub const PLAYER_LAYER: &str = "Player";
+pub const PLAYER_BULLET_LAYER: &str = "PlayerBullet";
+// Stats of the player at the start of the game, before any items.
 const PLAYER_START_SPEED: f32 = 100.;
-const BULLET_START_SPEED: f32 = 15.;
+const PLAYER_START_DAMAGE: f32 = 1.;
+const PLAYER_START_FIRE_DELAY: f32 = 0.5;
+const PLAYER_START_SHOT_SPEED: f32 = 225.;
+const PLAYER_START_RANGE: f32 = 400.;
+const PLAYER_START_LUCK: f32 = 0.;
 const PLAYER_START_HEALTH: usize = 42;
+const BULLET_RADIUS: f32 = 6.;
+// For how long the player cannot be hurt after being hurt.
+const PLAYER_INVULNERABILITY: f32 = 0.6;
+// How often the same enemy can hurt the player.
+const PLAYER_CONTACT_DAMAGE_COOLDOWN: f32 = 1.;
+// Bullets live a bit longer than it takes to fly their range, as walls may slow them down.
+const BULLET_LIFETIME_MARGIN: f32 = 1.5;
+// Bullets which run out of range burst into a splash, which grows and fades away.
+const SPLASH_DURATION: f32 = 0.25;
+const SPLASH_GROWTH: f32 = 1.8;
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(
-            SystemSet::on_update(AppState::InGame)
+        app.add_simulation_system_set(
+            SystemSet::new()
+                .with_run_criteria(run_in_state(AppState::InGame))
                 .with_system(control_player.label(ModifyVelocity))
                 .with_system(bullet_movement.label(ModifyVelocity))
                 .with_system(control_bullets)
@@ -31,7 +59,11 @@ impl Plugin for PlayerPlugin {
                 .with_system(player_takes_damage.label(ModifyHealth))
                 .with_system(player_dies.label(ReadDeaths)),
         )
-        .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(spawn_player));
+        .add_system_set(SystemSet::on_update(AppState::InGame).with_system(fade_splashes))
+        .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(spawn_player))
+        .add_system_set(
+            SystemSet::on_exit(AppState::InGame).with_system(despawn_player_and_bullets),
+        );
     }
 }
 
@@ -47,52 +79,171 @@ pub struct DirectionVector(pub Vec2);
 #[derive(Component, Copy, Clone)]
 pub struct Speed(pub f32);
 
+/// Everything that describes how strong the player is. Items change it.
+#[derive(Component, Copy, Clone, Debug, PartialEq)]
+pub struct PlayerStats {
+    // Movement speed, in pixels per second.
+    pub speed: f32,
+    // Damage of every bullet (rounded, but never less than 1).
+    pub damage: f32,
+    // Seconds between two shots.
+    pub fire_delay: f32,
+    // Speed of bullets, in pixels per second.
+    pub shot_speed: f32,
+    // How far bullets fly, in pixels.
+    pub range: f32,
+    // Makes rare items drop more often (description in file items.rs).
+    pub luck: f32,
+}
+
+impl Default for PlayerStats {
+    fn default() -> PlayerStats {
+        PlayerStats {
+            speed: PLAYER_START_SPEED,
+            damage: PLAYER_START_DAMAGE,
+            fire_delay: PLAYER_START_FIRE_DELAY,
+            shot_speed: PLAYER_START_SHOT_SPEED,
+            range: PLAYER_START_RANGE,
+            luck: PLAYER_START_LUCK,
+        }
+    }
+}
+
+impl PlayerStats {
+    pub fn bullet_damage(&self) -> usize {
+        self.damage.round().max(1.) as usize
+    }
+}
+
+// How much further and for how long a bullet can still fly.
+#[derive(Component, Clone, Copy)]
+pub struct BulletRange {
+    distance_left: f32,
+    time_left: f32,
+    // Where the bullet was in the previous tick, to know how far it has really moved.
+    last_position: Option<Vec2>,
+}
+
+impl BulletRange {
+    pub fn new(range: f32, speed: f32) -> BulletRange {
+        BulletRange {
+            distance_left: range,
+            time_left: range / speed.max(1.) * BULLET_LIFETIME_MARGIN,
+            last_position: None,
+        }
+    }
+
+    fn travel_to(&mut self, position: Vec2) {
+        if let Some(last_position) = self.last_position {
+            self.distance_left -= last_position.distance(position);
+        }
+        self.last_position = Some(position);
+        self.time_left -= SIMULATION_TICK;
+    }
+
+    pub fn distance_left(&self) -> f32 {
+        self.distance_left
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.distance_left <= 0. || self.time_left <= 0.
+    }
+}
+
+#[derive(Component)]
+struct Splash {
+    remaining: f32,
+}
+
+// Time left until the player can shoot again.
+#[derive(Component, Default)]
+pub struct FireCooldown(f32);
+
 #[derive(Bundle)]
 pub struct PlayerBundle {
     marker: PlayerMarker,
-    speed: Speed,
+    stats: PlayerStats,
+    fire_cooldown: FireCooldown,
+    inventory: Inventory,
+    projectile_modifiers: ProjectileModifiers,
     #[bundle]
     move_system_bundle: MoveSystemObjectWithVelocity,
     health_data: HealthData,
+    invulnerability: Invulnerability,
+    damage_cooldowns: DamageCooldowns,
 }
 
 impl PlayerBundle {
-    pub fn new(hitbox: Hitbox) -> PlayerBundle {
+    pub fn new(hitbox: Hitbox, layers: CollisionLayers) -> PlayerBundle {
         PlayerBundle {
             marker: PlayerMarker,
-            speed: Speed(PLAYER_START_SPEED),
-            move_system_bundle: MoveSystemObjectWithVelocity::new_with_vel_0(
-                MoveObjectType::Player,
-                hitbox,
-            ),
+            stats: PlayerStats::default(),
+            fire_cooldown: FireCooldown::default(),
+            inventory: Inventory::default(),
+            projectile_modifiers: ProjectileModifiers::default(),
+            move_system_bundle: MoveSystemObjectWithVelocity::new_with_vel_0(layers, hitbox),
             health_data: HealthData::new_healthy(PLAYER_START_HEALTH),
+            invulnerability: Invulnerability::new(PLAYER_INVULNERABILITY),
+            damage_cooldowns: DamageCooldowns::new(PLAYER_CONTACT_DAMAGE_COOLDOWN),
         }
     }
 }
 
+// Used both for bullets of the player (PLAYER_BULLET_LAYER) and of enemies (ENEMY_BULLET_LAYER).
 #[derive(Bundle)]
 pub struct BulletBundle {
     marker: BulletMarker,
     speed: Speed,
     direction: DirectionVector,
+    damage: Damage,
+    range: BulletRange,
     #[bundle]
     move_system_bundle: MoveSystemObjectWithVelocity,
+    continuous_collision: ContinuousCollision,
 }
 
 impl BulletBundle {
-    pub fn new(hitbox: Hitbox, vel: Vec2) -> BulletBundle {
+    pub fn new(
+        layers: CollisionLayers,
+        hitbox: Hitbox,
+        direction: Vec2,
+        speed: f32,
+        damage: usize,
+        range: f32,
+    ) -> BulletBundle {
         BulletBundle {
             marker: BulletMarker,
-            speed: Speed(BULLET_START_SPEED),
-            direction: DirectionVector(vel),
-            move_system_bundle: MoveSystemObjectWithVelocity::new_with_vel_0(
-                MoveObjectType::PlayerBullet,
-                hitbox,
-            ),
+            speed: Speed(speed),
+            direction: DirectionVector(direction),
+            damage: Damage(damage),
+            range: BulletRange::new(range, speed),
+            move_system_bundle: MoveSystemObjectWithVelocity::new_with_vel_0(layers, hitbox),
+            continuous_collision: ContinuousCollision,
         }
     }
 }
 
+pub fn spawn_bullet(
+    commands: &mut Commands,
+    bullet: BulletBundle,
+    position: Vec2,
+    sprite: Sprite,
+    texture: Handle<Image>,
+) -> Entity {
+    commands
+        .spawn_bundle(bullet)
+        .insert_bundle(SpriteBundle {
+            sprite,
+            texture,
+            transform: Transform {
+                translation: position.extend(2.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .id()
+}
+
 fn get_direction_from_keyboard(
     keyboard_input: &Res<Input<KeyCode>>,
     up: KeyCode,
@@ -117,16 +268,21 @@ fn get_direction_from_keyboard(
     Vec2::new(x, y).normalize_or_zero()
 }
 
-pub fn spawn_player(mut commands: Commands, textures: Res<Vec<TextureWrapper>>) {
+pub fn spawn_player(
+    mut commands: Commands,
+    textures: Res<Vec<TextureWrapper>>,
+    collision_matrix: Res<CollisionMatrix>,
+) {
     let wrapper = textures
         .iter()
         .find(|&x| x.owner_type == EntityType::Player);
     if let Some(texture_wrapper) = wrapper {
         // Spawning player.
         commands
-            .spawn_bundle(player::PlayerBundle::new(hitbox::Hitbox::new_rectangle(
-                Vec2::new(30., 30.),
-            )))
+            .spawn_bundle(player::PlayerBundle::new(
+                hitbox::Hitbox::new_rectangle(Vec2::new(30., 30.)),
+                collision_matrix.layers(PLAYER_LAYER),
+            ))
             .insert_bundle(SpriteBundle {
                 sprite: Sprite {
                     custom_size: Some(Vec2::new(30., 30.)),
@@ -145,10 +301,10 @@ pub fn spawn_player(mut commands: Commands, textures: Res<Vec<TextureWrapper>>)
 }
 
 pub fn control_player(
-    mut player: Query<(&mut VelocityVector, &Speed), (With<PlayerMarker>)>,
+    mut player: Query<(&mut VelocityVector, &PlayerStats), (With<PlayerMarker>)>,
     keyboard_input: Res<Input<KeyCode>>,
 ) {
-    if let Ok((mut vel, &Speed(speed))) = player.get_single_mut() {
+    if let Ok((mut vel, stats)) = player.get_single_mut() {
         let direction = get_direction_from_keyboard(
             &keyboard_input,
             KeyCode::W,
@@ -156,7 +312,7 @@ pub fn control_player(
             KeyCode::A,
             KeyCode::D,
         );
-        let new_vel = direction * speed;
+        let new_vel = direction * stats.speed;
         vel.0 += new_vel;
     }
 }
@@ -165,53 +321,81 @@ fn player_takes_damage(
     mut collision_reade: EventReader<CollisionEvent>,
     mut damage_writer: EventWriter<TakeDamageEvent>,
     mut players: Query<(Entity), (With<PlayerMarker>)>,
-    mut enemies: Query<(Entity), (With<EnemyMarker>)>,
-    mut bullets: Query<(Entity), (With<BulletMarker>)>,
+    mut enemies: Query<(&ContactDamage), (With<EnemyMarker>)>,
+    mut bullets: Query<(&Damage), (With<BulletMarker>)>,
 ) {
     for collision in collision_reade.iter() {
-        if let (Ok(player), Ok(bullet)) = (
+        if let (Ok(player), Ok(&Damage(damage))) = (
             players.get(collision.object_id),
             bullets.get(collision.collided_with_id),
         ) {
             damage_writer.send(TakeDamageEvent {
                 id: player,
-                amount: 1,
+                amount: damage,
+                source: Some(collision.collided_with_id),
             });
-        } else if let (Ok(player), Ok(enemie)) = (
+        } else if let (Ok(player), Ok(&ContactDamage(damage))) = (
             players.get(collision.object_id),
             enemies.get(collision.collided_with_id),
         ) {
-            // Now its the same as for collision with bullet,
-            // but in the futer calculating damage amount might be different
             damage_writer.send(TakeDamageEvent {
                 id: player,
-                amount: 1,
+                amount: damage,
+                source: Some(collision.collided_with_id),
             });
         }
     }
 }
 
+// The game freezes and the death sequence starts (description in file game_over.rs).
 fn player_dies(
-    mut commands: Commands,
     mut death_reader: EventReader<DeathEvent>,
     mut players: Query<(Entity), (With<PlayerMarker>)>,
+    mut state: ResMut<State<AppState>>,
 ) {
     for death in death_reader.iter() {
         if let Ok(plyer) = players.get(death.id) {
             info!("Zed's Dead");
+            // The state changes in the next frame, so the player may die
+            // once more in the meantime, but the game should end only once.
+            if state.push(AppState::Dying).is_err() {
+                return;
+            }
         }
     }
 }
 
+// Everything the player has left in the game.
+type PlayerEntityFilter = Or<(With<PlayerMarker>, With<BulletMarker>, With<Splash>)>;
+
+fn despawn_player_and_bullets(mut commands: Commands, entities: Query<Entity, PlayerEntityFilter>) {
+    for entity in entities.iter() {
+        commands.entity(entity).despawn();
+    }
+}
+
 pub fn control_bullets(
     mut commands: Commands,
-    mut player: Query<&Transform, With<PlayerMarker>>,
-    mut timer: ResMut<Timer>,
+    mut player: Query<
+        (
+            &Transform,
+            &PlayerStats,
+            &ProjectileModifiers,
+            &mut FireCooldown,
+        ),
+        With<PlayerMarker>,
+    >,
     keyboard_input: Res<Input<KeyCode>>,
-    time: Res<Time>,
+    textures: Res<Vec<TextureWrapper>>,
+    collision_matrix: Res<CollisionMatrix>,
 ) {
-    timer.tick(time.delta());
-    if let Ok(mut player_tf) = player.get_single_mut() {
+    let texture = textures
+        .iter()
+        .find(|&x| x.owner_type == EntityType::Bullet)
+        .map(|x| x.texture.clone())
+        .unwrap_or_default();
+    if let Ok((player_tf, stats, modifiers, mut cooldown)) = player.get_single_mut() {
+        cooldown.0 = (cooldown.0 - SIMULATION_TICK).max(0.);
         let shoot_direction = get_direction_from_keyboard(
             &keyboard_input,
             KeyCode::Up,
@@ -222,76 +406,208 @@ pub fn control_bullets(
 
         if shoot_direction != Vec2::new(0., 0.) {
             let mut spawn_bullets = || {
-                commands
-                    .spawn_bundle(BulletBundle::new(
-                        hitbox::Hitbox::new_rectangle(Vec2::new(7., 25.)),
-                        shoot_direction * BULLET_START_SPEED,
-                    ))
-                    .insert_bundle(SpriteBundle {
-                        sprite: Sprite {
-                            color: Color::from([30., 130., 240.]),
-                            custom_size: Some(Vec2::new(7., 25.)),
-                            ..Default::default()
-                        },
-                        transform: Transform {
-                            translation: Vec3::new(
-                                player_tf.translation.x,
-                                player_tf.translation.y,
-                                2.0,
-                            ),
+                let hitbox = hitbox::Hitbox::new_circle(BULLET_RADIUS);
+                let directions = modifiers.shot_directions(shoot_direction);
+                let in_volley = directions.len() > 1;
+                for direction in directions {
+                    let bullet = spawn_bullet(
+                        &mut commands,
+                        BulletBundle::new(
+                            collision_matrix.layers(PLAYER_BULLET_LAYER),
+                            hitbox,
+                            direction,
+                            stats.shot_speed,
+                            stats.bullet_damage(),
+                            stats.range,
+                        ),
+                        player_tf.translation.truncate(),
+                        Sprite {
+                            custom_size: Some(hitbox.size()),
                             ..Default::default()
                         },
-                        ..Default::default()
-                    });
+                        texture.clone(),
+                    );
+                    modifiers.apply(&mut commands, bullet, &collision_matrix, in_volley);
+                }
             };
 
-            if timer.finished() {
+            if cooldown.0 <= 0. {
                 spawn_bullets();
-                timer.reset();
+                cooldown.0 = stats.fire_delay;
             }
         }
     }
 }
 
-fn bullet_movement(
+fn spawn_splash(commands: &mut Commands, position: Vec2, sprite: Sprite, texture: Handle<Image>) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite,
+            texture,
+            transform: Transform {
+                translation: position.extend(2.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(Splash {
+            remaining: SPLASH_DURATION,
+        });
+}
+
+// Splashes are only visual, so they do not need to be a part of the simulation.
+fn fade_splashes(
     mut commands: Commands,
-    win_size: Res<WinSize>,
-    mut query: Query<
-        (
-            Entity,
-            &DirectionVector,
-            &mut VelocityVector,
-            &Speed,
-            &Transform,
-        ),
-        (With<BulletMarker>),
-    >,
+    time: Res<Time>,
+    mut splashes: Query<(Entity, &mut Splash, &mut Transform, &mut Sprite)>,
 ) {
-    for (bullet_entity, direction, mut vel, &Speed(speed), bullet_tf) in query.iter_mut() {
-        let new_vel = direction.0 * speed;
-        vel.0 += new_vel;
+    for (entity, mut splash, mut transform, mut sprite) in splashes.iter_mut() {
+        splash.remaining -= time.delta_seconds();
+        if splash.remaining <= 0. {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let progress = 1. - splash.remaining / SPLASH_DURATION;
+        transform.scale = Vec3::splat(1. + (SPLASH_GROWTH - 1.) * progress);
+        sprite.color.set_a(1. - progress);
+    }
+}
+
+type MovingBulletItem<'a> = (
+    Entity,
+    &'a DirectionVector,
+    &'a mut VelocityVector,
+    &'a Speed,
+    &'a mut BulletRange,
+    &'a Transform,
+    &'a Sprite,
+    &'a Handle<Image>,
+);
 
-        // Delete entity if it's outside of the window.
-        let translation = &bullet_tf.translation;
-        if translation.y > win_size.height || translation.x > win_size.width {
+fn bullet_movement(
+    mut commands: Commands,
+    nav_grid: Res<NavGrid>,
+    mut query: Query<MovingBulletItem, (With<BulletMarker>)>,
+) {
+    let room = nav_grid.bounds();
+    for (bullet_entity, direction, mut vel, &Speed(speed), mut range, bullet_tf, sprite, texture) in
+        query.iter_mut()
+    {
+        let position = bullet_tf.translation.truncate();
+        range.travel_to(position);
+        if range.is_exhausted() {
             commands.entity(bullet_entity).despawn();
+            spawn_splash(&mut commands, position, sprite.clone(), texture.clone());
+            continue;
+        }
+        // Bullets which somehow left the room would fly on for nothing.
+        if let Some((min, max)) = room {
+            if position.cmplt(min).any() || position.cmpgt(max).any() {
+                commands.entity(bullet_entity).despawn();
+                continue;
+            }
         }
+
+        let new_vel = direction.0 * speed;
+        vel.0 += new_vel;
     }
 }
 
+type CollidingBulletItem<'a> = (
+    &'a Transform,
+    &'a Hitbox,
+    &'a Speed,
+    &'a Damage,
+    &'a BulletRange,
+    &'a mut DirectionVector,
+    &'a Sprite,
+    &'a Handle<Image>,
+    Option<&'a mut Piercing>,
+    Option<&'a mut Bouncing>,
+    Option<&'a Splitting>,
+);
+
 fn on_collision_bullet(
     mut commands: Commands,
     mut collision_reader: EventReader<CollisionEvent>,
-    query_bullet: Query<(Entity), (With<BulletMarker>)>,
-    query_entity: Query<(Entity), (Or<(With<EnemyMarker>, With<PlayerMarker>)>)>, // , With<EnemyMarker>
+    mut query_bullet: Query<CollidingBulletItem, (With<BulletMarker>)>,
+    collision_matrix: Res<CollisionMatrix>,
 ) {
-    for collision in collision_reader
-        .iter()
-        .filter(|c_ev| c_ev.object_type == PlayerBullet)
-    {
-        let bullet = query_bullet.get(collision.object_id);
-        if let Ok((bullet_entity)) = bullet {
-            commands.entity(bullet_entity).despawn();
+    let enemy_layer = collision_matrix.layer(ENEMY_LAYER);
+    let obstacle_layer = collision_matrix.layer(OBSTACLE_LAYER);
+    // Bullets may hit a few things at once, but they stop only once.
+    let mut stopped = vec![];
+    for collision in collision_reader.iter() {
+        let bullet_entity = collision.object_id;
+        if stopped.contains(&bullet_entity) {
+            continue;
+        }
+        let (
+            bullet_tf,
+            hitbox,
+            &Speed(speed),
+            &Damage(damage),
+            range,
+            mut direction,
+            sprite,
+            texture,
+            piercing,
+            bouncing,
+            splitting,
+        ) = match query_bullet.get_mut(bullet_entity) {
+            Ok(bullet) => bullet,
+            Err(_) => continue,
+        };
+
+        let hit = collision.collided_with_layers;
+        if hit.is_in(enemy_layer) {
+            if let Some(mut piercing) = piercing {
+                if piercing.pierce(collision.collided_with_id) {
+                    continue;
+                }
+            }
+        } else if hit.is_in(obstacle_layer) {
+            if let Some(mut bouncing) = bouncing.filter(|bouncing| bouncing.remaining > 0) {
+                // Touching the obstacle again while already flying away does not count.
+                if let Some(bounced) = bounce(direction.0, collision.normal) {
+                    direction.0 = bounced;
+                    bouncing.remaining -= 1;
+                }
+                continue;
+            }
+        }
+
+        commands.entity(bullet_entity).despawn();
+        stopped.push(bullet_entity);
+
+        if let Some(&Splitting { count }) = splitting {
+            let piece_hitbox =
+                Hitbox::new_circle(hitbox.half_extents().max_element() * SPLIT_SIZE_FACTOR);
+            let piece_damage = ((damage as f32 * SPLIT_SIZE_FACTOR).round() as usize).max(1);
+            // The pieces appear inside the enemy that has been hit, which must not be hit again.
+            let spared = hit.is_in(enemy_layer).then_some(collision.collided_with_id);
+            for piece_direction in split_directions(direction.0, count) {
+                let piece = spawn_bullet(
+                    &mut commands,
+                    BulletBundle::new(
+                        bullet_layers(&collision_matrix, spared.is_some(), true),
+                        piece_hitbox,
+                        piece_direction,
+                        speed,
+                        piece_damage,
+                        range.distance_left(),
+                    ),
+                    bullet_tf.translation.truncate(),
+                    Sprite {
+                        custom_size: Some(piece_hitbox.size()),
+                        ..sprite.clone()
+                    },
+                    texture.clone(),
+                );
+                if let Some(enemy) = spared {
+                    commands.entity(piece).insert(Piercing::sparing(enemy));
+                }
+            }
         }
     }
 }
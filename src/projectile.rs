@@ -0,0 +1,271 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::collision_layers::{CollisionLayers, CollisionMatrix};
+use crate::common::rotate;
+use crate::enemy::{EnemyMarker, ENEMY_LAYER};
+use crate::move_system::ModifyVelocity;
+use crate::player::{BulletMarker, DirectionVector, PLAYER_BULLET_LAYER};
+use crate::simulation::{run_in_state, SimulationAppExt, SIMULATION_TICK};
+use crate::AppState;
+
+/*
+    Projectile modifiers - components changing how bullets of the player fly
+    and what happens when they hit something. Items grant them
+    (description in file items.rs), e.g. "projectile": [ { "Piercing": { "count": 2 } } ].
+
+        Spread    : every shot is a fan of "count" bullets, "angle" degrees apart,
+        Piercing  : the bullet flies through "count" enemies (hurting them) before it stops,
+        Homing    : the bullet turns towards the nearest enemy, at most "turn_rate" radians per second,
+        Bouncing  : the bullet bounces off obstacles "count" times before it stops,
+        Splitting : the bullet breaks into "count" smaller ones when it stops
+                    (the pieces fly through the enemy that has been hit, without hurting it).
+
+    Modifiers of all collected items are combined into ProjectileModifiers of the player:
+    counts add up (Spread adds the extra bullets), the biggest angle and turn rate win.
+    Every bullet shot gets them as separate components (Spread only matters when shooting).
+
+    Bullets of one shot (and pieces of a split bullet) do not destroy each other,
+    and piercing bullets are not blocked by enemies - both get their own CollisionLayers.
+*/
+
+// Enemies further away than that are not noticed by homing bullets.
+const HOMING_RANGE: f32 = 250.;
+// Pieces of a split bullet are smaller and weaker.
+pub const SPLIT_SIZE_FACTOR: f32 = 0.6;
+
+pub struct ProjectilePlugin;
+
+impl Plugin for ProjectilePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_simulation_system_set(
+            SystemSet::new()
+                .with_run_criteria(run_in_state(AppState::InGame))
+                .with_system(steer_homing_bullets.before(ModifyVelocity)),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum ProjectileModifier {
+    Spread { count: usize, angle: f32 },
+    Piercing { count: usize },
+    Homing { turn_rate: f32 },
+    Bouncing { count: usize },
+    Splitting { count: usize },
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct Spread {
+    pub count: usize,
+    // In degrees.
+    pub angle: f32,
+}
+
+#[derive(Component, Clone, Default)]
+pub struct Piercing {
+    pub remaining: usize,
+    // Enemies the bullet is flying through or has already flown through.
+    pierced: Vec<Entity>,
+    // Enemy the bullet neither stops at nor hurts, e.g. the one its split bullet has hit.
+    spared: Option<Entity>,
+}
+
+impl Piercing {
+    pub fn new(count: usize) -> Piercing {
+        Piercing {
+            remaining: count,
+            pierced: vec![],
+            spared: None,
+        }
+    }
+
+    pub fn sparing(enemy: Entity) -> Piercing {
+        Piercing {
+            spared: Some(enemy),
+            ..Piercing::new(0)
+        }
+    }
+
+    pub fn spares(&self, enemy: Entity) -> bool {
+        self.spared == Some(enemy)
+    }
+
+    // Whether the bullet flies on after touching the enemy.
+    pub fn pierce(&mut self, enemy: Entity) -> bool {
+        if self.spares(enemy) || self.pierced.contains(&enemy) {
+            return true;
+        }
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        self.pierced.push(enemy);
+        true
+    }
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct Homing {
+    pub turn_rate: f32,
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct Bouncing {
+    pub remaining: usize,
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct Splitting {
+    pub count: usize,
+}
+
+/// Projectile modifiers of the player, combined from all collected items.
+#[derive(Component, Clone, Copy, Default)]
+pub struct ProjectileModifiers {
+    pub spread: Option<Spread>,
+    pub piercing: usize,
+    pub homing: Option<Homing>,
+    pub bouncing: usize,
+    pub splitting: usize,
+}
+
+impl ProjectileModifiers {
+    pub fn add(&mut self, modifier: ProjectileModifier) {
+        match modifier {
+            ProjectileModifier::Spread { count, angle } => {
+                let spread = self.spread.get_or_insert(Spread {
+                    count: 1,
+                    angle: 0.,
+                });
+                spread.count += count.saturating_sub(1);
+                spread.angle = spread.angle.max(angle);
+            }
+            ProjectileModifier::Piercing { count } => self.piercing += count,
+            ProjectileModifier::Homing { turn_rate } => {
+                let homing = self.homing.get_or_insert(Homing { turn_rate: 0. });
+                homing.turn_rate = homing.turn_rate.max(turn_rate);
+            }
+            ProjectileModifier::Bouncing { count } => self.bouncing += count,
+            ProjectileModifier::Splitting { count } => self.splitting += count,
+        }
+    }
+
+    // Directions of all bullets of a shot in the given direction.
+    pub fn shot_directions(&self, direction: Vec2) -> Vec<Vec2> {
+        match self.spread {
+            Some(Spread { count, angle }) => fan(direction, count, angle.to_radians()),
+            None => vec![direction],
+        }
+    }
+
+    // Gives the modifiers to a new bullet of the player.
+    pub fn apply(
+        &self,
+        commands: &mut Commands,
+        bullet: Entity,
+        matrix: &CollisionMatrix,
+        in_volley: bool,
+    ) {
+        let mut bullet = commands.entity(bullet);
+        if self.piercing > 0 {
+            bullet.insert(Piercing::new(self.piercing));
+        }
+        if let Some(homing) = self.homing {
+            bullet.insert(homing);
+        }
+        if self.bouncing > 0 {
+            bullet.insert(Bouncing {
+                remaining: self.bouncing,
+            });
+        }
+        if self.splitting > 0 {
+            bullet.insert(Splitting {
+                count: self.splitting,
+            });
+        }
+        if self.piercing > 0 || in_volley {
+            bullet.insert(bullet_layers(matrix, self.piercing > 0, in_volley));
+        }
+    }
+}
+
+// Collision layers of a bullet of the player, changed by its modifiers.
+pub fn bullet_layers(matrix: &CollisionMatrix, piercing: bool, in_volley: bool) -> CollisionLayers {
+    let mut layers = matrix.layers(PLAYER_BULLET_LAYER);
+    let enemy = matrix.layer(ENEMY_LAYER);
+    if piercing {
+        layers.blocks &= !enemy;
+        layers.reports |= enemy;
+    }
+    if in_volley {
+        layers.blocks &= !layers.membership;
+    }
+    layers
+}
+
+// Directions "angle" radians apart, centred around the given one.
+pub fn fan(direction: Vec2, count: usize, angle: f32) -> Vec<Vec2> {
+    (0..count)
+        .map(|i| {
+            // Offsets from the direction, e.g. -1, 0, 1 for three bullets.
+            let offset = i as f32 - (count - 1) as f32 / 2.;
+            rotate(direction, offset * angle)
+        })
+        .collect()
+}
+
+// Directions of the pieces of a split bullet, evenly spread around.
+// The first one is turned half a step away from the direction of the bullet,
+// so two pieces fly off to the sides instead of ahead and back.
+pub fn split_directions(direction: Vec2, count: usize) -> Vec<Vec2> {
+    (0..count)
+        .map(|i| {
+            rotate(
+                direction,
+                std::f32::consts::TAU * (i as f32 + 0.5) / count as f32,
+            )
+        })
+        .collect()
+}
+
+// Direction after bouncing off a surface, if the bullet is moving into it.
+pub fn bounce(direction: Vec2, normal: Vec2) -> Option<Vec2> {
+    let into_surface = direction.dot(normal);
+    if into_surface <= 0. {
+        return None;
+    }
+    Some(direction - 2. * into_surface * normal)
+}
+
+fn steer_homing_bullets(
+    mut bullets: Query<(&Transform, &mut DirectionVector, &Homing), With<BulletMarker>>,
+    enemies: Query<&Transform, With<EnemyMarker>>,
+) {
+    for (transform, mut direction, homing) in bullets.iter_mut() {
+        let position = transform.translation.truncate();
+        let target = enemies
+            .iter()
+            .map(|enemy| enemy.translation.truncate())
+            .filter(|enemy| enemy.distance(position) <= HOMING_RANGE)
+            .min_by(|a, b| a.distance(position).total_cmp(&b.distance(position)));
+        let to_target = match target {
+            Some(target) => (target - position).normalize_or_zero(),
+            None => continue,
+        };
+        if to_target == Vec2::ZERO {
+            continue;
+        }
+        let current = direction.0.y.atan2(direction.0.x);
+        let wanted = to_target.y.atan2(to_target.x);
+        let mut turn = wanted - current;
+        // The shorter way around.
+        if turn > std::f32::consts::PI {
+            turn -= std::f32::consts::TAU;
+        } else if turn < -std::f32::consts::PI {
+            turn += std::f32::consts::TAU;
+        }
+        let max_turn = homing.turn_rate * SIMULATION_TICK;
+        direction.0 = rotate(direction.0, turn.clamp(-max_turn, max_turn));
+    }
+}
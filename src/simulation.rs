@@ -0,0 +1,217 @@
+use bevy::core::{FixedTimestep, FixedTimesteps};
+use bevy::ecs::schedule::{IntoSystemDescriptor, ShouldRun};
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::env;
+
+use crate::move_system::VelocityVector;
+use crate::AppState;
+
+/*
+    Fixed timestep simulation.
+
+    Gameplay (moving, health, AI, etc.) runs in SimulationStage, which is executed
+    every SIMULATION_TICK seconds of real time - zero, one or more times per frame.
+    Systems running there should use SIMULATION_TICK instead of Time::delta,
+    so the result of the game does not depend on the framerate.
+
+    Inside the SimulationStage there are three stages:
+        Prepare : restores positions of moving objects from the last tick,
+        Update  : the actual gameplay, add systems here with add_simulation_system(_set),
+        Finish  : remembers positions of moving objects after the tick.
+
+    Between ticks, the Transforms of moving objects are interpolated,
+    so the movement on the screen stays smooth regardless of the framerate.
+    Because of that, positions of moving objects should only be modified
+    by systems running in the SimulationStage.
+
+    Randomness of the gameplay (spawning, AI, rewards) should come from the GameRng
+    resource instead of rand::thread_rng, and only be drawn in the SimulationStage,
+    so a game can be replayed tick by tick. GameRng is seeded at the start of every game,
+    with the value of the SEED_VARIABLE environment variable if it is set.
+*/
+
+pub const SIMULATION_TICK: f32 = 1. / 60.;
+const SIMULATION_TIMESTEP: &str = "simulation_timestep";
+const SEED_VARIABLE: &str = "GAME_SEED";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, StageLabel)]
+enum SimulationStep {
+    Prepare,
+    Update,
+    Finish,
+}
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        let mut simulation = Schedule::default()
+            .with_run_criteria(
+                FixedTimestep::step(SIMULATION_TICK as f64).with_label(SIMULATION_TIMESTEP),
+            )
+            .with_stage(SimulationStep::Prepare, SystemStage::parallel())
+            .with_stage(SimulationStep::Update, SystemStage::parallel())
+            .with_stage(SimulationStep::Finish, SystemStage::parallel());
+        simulation
+            .add_system_to_stage(SimulationStep::Prepare, restore_simulated_positions)
+            .add_system_to_stage(SimulationStep::Prepare, track_new_objects)
+            .add_system_to_stage(SimulationStep::Finish, record_simulated_positions);
+
+        app.insert_resource(GameRng::new(run_seed()))
+            .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(seed_game_rng))
+            .add_stage_after(CoreStage::Update, SimulationStage, simulation)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolate_positions.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+pub trait SimulationAppExt {
+    fn add_simulation_system<Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self;
+    fn add_simulation_system_set(&mut self, system_set: SystemSet) -> &mut Self;
+}
+
+impl SimulationAppExt for App {
+    fn add_simulation_system<Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self {
+        self.stage(SimulationStage, |simulation: &mut Schedule| {
+            simulation.add_system_to_stage(SimulationStep::Update, system)
+        })
+    }
+
+    fn add_simulation_system_set(&mut self, system_set: SystemSet) -> &mut Self {
+        self.stage(SimulationStage, |simulation: &mut Schedule| {
+            simulation.add_system_set_to_stage(SimulationStep::Update, system_set)
+        })
+    }
+}
+
+/// Run criteria for simulation systems that should only work in the given state.
+/// SystemSet::on_update cannot be used outside of the stage driving the state.
+pub fn run_in_state(state: AppState) -> impl FnMut(Res<State<AppState>>) -> ShouldRun {
+    move |current: Res<State<AppState>>| {
+        if *current.current() == state {
+            ShouldRun::Yes
+        } else {
+            ShouldRun::No
+        }
+    }
+}
+
+/// The only source of randomness for the gameplay.
+pub struct GameRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+// The seed from SEED_VARIABLE, or a random one.
+fn run_seed() -> u64 {
+    match env::var(SEED_VARIABLE).map(|seed| seed.parse()) {
+        Ok(Ok(seed)) => seed,
+        Ok(Err(_)) => {
+            eprintln!("[run_seed] {} is not a number.", SEED_VARIABLE);
+            rand::random()
+        }
+        Err(_) => rand::random(),
+    }
+}
+
+fn seed_game_rng(mut rng: ResMut<GameRng>) {
+    *rng = GameRng::new(run_seed());
+    info!("Seed of the game: {}", rng.seed());
+}
+
+/// Positions of a moving object after the last two ticks.
+#[derive(Component, Copy, Clone)]
+pub struct SimulatedPosition {
+    previous: Vec2,
+    current: Vec2,
+}
+
+// Moving objects which have not been simulated yet.
+type NewObjectFilter = (With<VelocityVector>, Without<SimulatedPosition>);
+
+fn track_new_objects(
+    mut commands: Commands,
+    objects: Query<(Entity, &Transform), NewObjectFilter>,
+) {
+    for (entity, transform) in objects.iter() {
+        let position = transform.translation.truncate();
+        commands.entity(entity).insert(SimulatedPosition {
+            previous: position,
+            current: position,
+        });
+    }
+}
+
+// Undo the interpolation, so the simulation continues from the exact positions.
+fn restore_simulated_positions(mut objects: Query<(&mut Transform, &mut SimulatedPosition)>) {
+    for (mut transform, mut simulated) in objects.iter_mut() {
+        simulated.previous = simulated.current;
+        transform.translation = simulated.current.extend(transform.translation.z);
+    }
+}
+
+fn record_simulated_positions(mut objects: Query<(&Transform, &mut SimulatedPosition)>) {
+    for (transform, mut simulated) in objects.iter_mut() {
+        simulated.current = transform.translation.truncate();
+    }
+}
+
+fn interpolate_positions(
+    fixed_timesteps: Res<FixedTimesteps>,
+    mut objects: Query<(&mut Transform, &SimulatedPosition)>,
+) {
+    let overstep = fixed_timesteps
+        .get(SIMULATION_TIMESTEP)
+        .map(|timestep| timestep.overstep_percentage() as f32)
+        .unwrap_or(1.)
+        .min(1.);
+    for (mut transform, simulated) in objects.iter_mut() {
+        let position = simulated.previous.lerp(simulated.current, overstep);
+        transform.translation = position.extend(transform.translation.z);
+    }
+}
@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+/*
+    Uniform grid used as a broad phase for collision checks.
+
+    Every object is inserted into all the cells its bounding box touches.
+    Only objects sharing at least one cell can possibly collide, so instead
+    of testing every pair of objects we only test pairs that meet in a cell.
+
+    Objects are identified by indices (e.g. positions in a Vec built by
+    the caller), so the grid knows nothing about entities or hitboxes.
+
+    Objects near a segment (e.g. a ray) can be found with query_segment,
+    which only looks into the cells along the segment, not into its whole bounding box.
+*/
+
+type Cell = (i32, i32);
+
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<Cell, Vec<usize>>,
+    // Range of the cells with any object in them.
+    min_cell: Cell,
+    max_cell: Cell,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f32) -> SpatialHash {
+        SpatialHash {
+            cell_size,
+            cells: HashMap::new(),
+            min_cell: (i32::MAX, i32::MAX),
+            max_cell: (i32::MIN, i32::MIN),
+        }
+    }
+
+    fn cell_of(&self, point: Vec2) -> Cell {
+        (
+            (point.x / self.cell_size).floor() as i32,
+            (point.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    fn cells_between(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = Cell> {
+        let (min_x, min_y) = self.cell_of(min);
+        let (max_x, max_y) = self.cell_of(max);
+        (min_x..=max_x).flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+    }
+
+    // Inserts an object occupying the box [min, max].
+    pub fn insert(&mut self, index: usize, min: Vec2, max: Vec2) {
+        for cell in self.cells_between(min, max) {
+            self.cells.entry(cell).or_default().push(index);
+        }
+        let (min_x, min_y) = self.cell_of(min);
+        let (max_x, max_y) = self.cell_of(max);
+        self.min_cell = (self.min_cell.0.min(min_x), self.min_cell.1.min(min_y));
+        self.max_cell = (self.max_cell.0.max(max_x), self.max_cell.1.max(max_y));
+    }
+
+    // Box containing all the objects, if there are any.
+    pub fn bounds(&self) -> Option<(Vec2, Vec2)> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let corner = |(x, y): Cell| Vec2::new(x as f32, y as f32) * self.cell_size;
+        let (max_x, max_y) = self.max_cell;
+        Some((corner(self.min_cell), corner((max_x + 1, max_y + 1))))
+    }
+
+    // Returns sorted indices of objects that share a cell with the box [min, max].
+    pub fn query(&self, min: Vec2, max: Vec2) -> Vec<usize> {
+        let mut found = vec![];
+        for cell in self.cells_between(min, max) {
+            if let Some(indices) = self.cells.get(&cell) {
+                found.extend_from_slice(indices);
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    // Returns sorted indices of objects that share a cell with the segment from `from` to `to`
+    // widened by the margin on each side (e.g. half extents of a shape moved along it).
+    pub fn query_segment(&self, from: Vec2, to: Vec2, margin: Vec2) -> Vec<usize> {
+        let (min_x, min_y) = self.cell_of(from.min(to) - margin);
+        let (max_x, max_y) = self.cell_of(from.max(to) + margin);
+        let mut found = vec![];
+        // Cells outside of the occupied range are empty anyway.
+        for x in min_x.max(self.min_cell.0)..=max_x.min(self.max_cell.0) {
+            for y in min_y.max(self.min_cell.1)..=max_y.min(self.max_cell.1) {
+                let cell_min = Vec2::new(x as f32, y as f32) * self.cell_size;
+                let cell_max = cell_min + Vec2::splat(self.cell_size);
+                if !segment_crosses_box(from, to, cell_min - margin, cell_max + margin) {
+                    continue;
+                }
+                if let Some(indices) = self.cells.get(&(x, y)) {
+                    found.extend_from_slice(indices);
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    // Returns all pairs (i, j), i < j, of objects sharing at least one cell.
+    // Pairs are sorted, so the order does not depend on the hashing.
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for indices in self.cells.values() {
+            for (k, &i) in indices.iter().enumerate() {
+                for &j in indices[(k + 1)..].iter() {
+                    pairs.push((i.min(j), i.max(j)));
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+        pairs
+    }
+}
+
+// Whether any point of the segment from `from` to `to` lies in the box [min, max].
+fn segment_crosses_box(from: Vec2, to: Vec2, min: Vec2, max: Vec2) -> bool {
+    let delta = to - from;
+    // Fractions of the segment at which it enters and leaves the box.
+    let mut enter: f32 = 0.;
+    let mut exit: f32 = 1.;
+    for axis in 0..2 {
+        if delta[axis] == 0. {
+            if from[axis] < min[axis] || from[axis] > max[axis] {
+                return false;
+            }
+            continue;
+        }
+        let first = (min[axis] - from[axis]) / delta[axis];
+        let second = (max[axis] - from[axis]) / delta[axis];
+        enter = enter.max(first.min(second));
+        exit = exit.min(first.max(second));
+    }
+    enter <= exit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_query_skips_cells_off_the_segment() {
+        let mut grid = SpatialHash::new(10.);
+        // On the diagonal, off the diagonal but in its bounding box, and next to the diagonal.
+        grid.insert(0, Vec2::new(21., 21.), Vec2::new(24., 24.));
+        grid.insert(1, Vec2::new(31., 1.), Vec2::new(34., 4.));
+        grid.insert(2, Vec2::new(41., 21.), Vec2::new(44., 24.));
+        let from = Vec2::new(0., 0.);
+        let to = Vec2::new(50., 50.);
+        assert_eq!(grid.query_segment(from, to, Vec2::ZERO), vec![0]);
+        assert_eq!(grid.query_segment(from, to, Vec2::splat(6.)), vec![0, 2]);
+        assert_eq!(grid.query(from, to), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn bounds_cover_all_objects() {
+        let mut grid = SpatialHash::new(10.);
+        assert!(grid.bounds().is_none());
+        grid.insert(0, Vec2::new(-5., 3.), Vec2::new(5., 8.));
+        grid.insert(1, Vec2::new(12., -15.), Vec2::new(14., -11.));
+        assert_eq!(
+            grid.bounds(),
+            Some((Vec2::new(-10., -20.), Vec2::new(20., 10.)))
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::hitbox::Hitbox;
+use crate::map::SpawnPoints;
+use crate::navigation::{NavGrid, NAV_CELL_SIZE};
+use crate::player::PlayerMarker;
+
+/*
+    Choosing where new enemies appear.
+
+    Add a SpawnPlacement parameter to a system spawning enemies.
+    Positions are chosen at random from the walkable cells of the NavGrid
+    (floor tiles without obstacles), so enemies never appear inside walls
+    or outside of the rendered room.
+    The enemy has to fit in the chosen place with some clearance from obstacles
+    and be far enough from the player, so it does not hit them right away.
+
+    If the rendered map defines spawn points, enemies appear around one of them
+    (far enough from the player) instead. Enemies spawned at once share a list
+    of taken places, so no two of them get the same cell.
+    When there is no place for the enemy (e.g. no map is rendered yet),
+    there is no position and the spawn should be skipped or retried later.
+*/
+
+// Space left between a new enemy and the obstacles around it.
+const OBSTACLE_CLEARANCE: f32 = 10.;
+// Minimal distance between a new enemy and the player.
+const PLAYER_CLEARANCE: f32 = 150.;
+// How far from a spawn point of the map enemies can appear.
+const SPAWN_POINT_SPREAD: f32 = 1.5 * NAV_CELL_SIZE;
+
+#[derive(SystemParam)]
+pub struct SpawnPlacement<'w, 's> {
+    nav_grid: Res<'w, NavGrid>,
+    spawn_points: Res<'w, SpawnPoints>,
+    player: Query<'w, 's, &'static Transform, With<PlayerMarker>>,
+}
+
+impl<'w, 's> SpawnPlacement<'w, 's> {
+    fn player_position(&self) -> Option<Vec2> {
+        self.player
+            .get_single()
+            .ok()
+            .map(|transform| transform.translation.truncate())
+    }
+
+    fn far_from_player(&self, position: Vec2) -> bool {
+        self.player_position()
+            .is_none_or(|player| player.distance(position) >= PLAYER_CLEARANCE)
+    }
+
+    // Number of cells around the cell of the enemy that have to be free.
+    fn clearance_in_cells(hitbox: &Hitbox) -> i32 {
+        let reach = hitbox.half_extents().max_element() + OBSTACLE_CLEARANCE;
+        ((reach - NAV_CELL_SIZE / 2.) / NAV_CELL_SIZE)
+            .ceil()
+            .max(0.) as i32
+    }
+
+    // Centres of the cells where an enemy with the hitbox fits and that satisfy the condition.
+    fn free_cells(&self, hitbox: &Hitbox, condition: impl Fn(Vec2) -> bool) -> Vec<Vec2> {
+        let radius = Self::clearance_in_cells(hitbox);
+        self.nav_grid
+            .walkable_cells()
+            .filter(|&cell| self.nav_grid.is_clear(cell, radius))
+            .map(|cell| self.nav_grid.center_of(cell))
+            .filter(|&position| condition(position))
+            .collect()
+    }
+
+    /// Whether the rendered map has any place for new enemies at all.
+    pub fn is_ready(&self) -> bool {
+        self.nav_grid.walkable_cells().next().is_some()
+    }
+
+    /// Position for a new enemy with the hitbox, anywhere on the map, but not in the taken places.
+    /// The position is added to the taken places.
+    pub fn anywhere(
+        &self,
+        hitbox: &Hitbox,
+        taken: &mut Vec<Vec2>,
+        rng: &mut impl Rng,
+    ) -> Option<Vec2> {
+        let spawn_points: Vec<Vec2> = self
+            .spawn_points
+            .0
+            .iter()
+            .copied()
+            .filter(|&point| self.far_from_player(point))
+            .collect();
+        if let Some(&point) = spawn_points.choose(rng) {
+            // Enemies spawned at the same point at once should not overlap.
+            if let Some(position) = self.near(point, SPAWN_POINT_SPREAD, hitbox, taken, rng) {
+                return Some(position);
+            }
+        }
+        let position = self
+            .free_cells(hitbox, |position| {
+                self.far_from_player(position) && !taken.contains(&position)
+            })
+            .choose(rng)
+            .copied()
+            // The player may be too close to every free cell of a small map.
+            .or_else(|| {
+                self.free_cells(hitbox, |position| !taken.contains(&position))
+                    .choose(rng)
+                    .copied()
+            });
+        taken.extend(position);
+        position
+    }
+
+    /// Position for a new enemy with the hitbox, within the distance from the point,
+    /// but not in the taken places. The position is added to the taken places.
+    pub fn near(
+        &self,
+        point: Vec2,
+        distance: f32,
+        hitbox: &Hitbox,
+        taken: &mut Vec<Vec2>,
+        rng: &mut impl Rng,
+    ) -> Option<Vec2> {
+        let position = self
+            .free_cells(hitbox, |position| {
+                position.distance(point) <= distance && !taken.contains(&position)
+            })
+            .choose(rng)
+            .copied();
+        taken.extend(position);
+        position
+    }
+}
@@ -0,0 +1,174 @@
+use bevy::prelude::*;
+
+use crate::common::rotate;
+use crate::navigation::{NavGrid, NAV_CELL_SIZE};
+use crate::spatial_hash::SpatialHash;
+
+/*
+    Steering behaviours of enemies, blended into their velocity by move_enemies
+    (in file enemy.rs) on top of the direction chosen by their state.
+
+        separation : pushes away from enemies that are too close,
+        cohesion   : pulls slightly towards the centre of nearby enemies,
+        avoidance  : turns away from obstacles right in front of the enemy,
+        arrival    : slows down when getting close to the target.
+
+    Separation and cohesion need the neighbours of every enemy, which are found
+    with a SpatialHash (Neighbourhood), so the cost does not grow with the square
+    of the number of enemies. The steering vectors are relative to the speed
+    of the enemy (length about 1 at most), so they can be simply added together.
+*/
+
+// Distance (between hitboxes) below which enemies push each other away.
+const SEPARATION_DISTANCE: f32 = 12.;
+// Enemies closer than that count as one group for cohesion.
+const COHESION_RADIUS: f32 = 80.;
+// How far ahead enemies look for obstacles.
+const AVOIDANCE_LOOKAHEAD: f32 = 40.;
+// Angle between the direction of movement and the side feelers, in radians.
+const AVOIDANCE_FEELER_ANGLE: f32 = 0.5;
+// Distance between the points of a feeler checked for obstacles.
+const AVOIDANCE_FEELER_STEP: f32 = NAV_CELL_SIZE / 3.;
+// Distance to the target from which enemies start slowing down.
+const ARRIVAL_RADIUS: f32 = 50.;
+
+pub const SEPARATION_WEIGHT: f32 = 1.2;
+pub const COHESION_WEIGHT: f32 = 0.15;
+pub const AVOIDANCE_WEIGHT: f32 = 0.8;
+
+#[derive(Clone, Copy)]
+pub struct Neighbour {
+    pub entity: Entity,
+    pub position: Vec2,
+    pub radius: f32,
+}
+
+/// Enemies put into a SpatialHash, so neighbours of every one of them can be found quickly.
+pub struct Neighbourhood {
+    neighbours: Vec<Neighbour>,
+    grid: SpatialHash,
+}
+
+impl Neighbourhood {
+    pub fn new(neighbours: Vec<Neighbour>) -> Neighbourhood {
+        let mut grid = SpatialHash::new(COHESION_RADIUS);
+        for (i, neighbour) in neighbours.iter().enumerate() {
+            grid.insert(i, neighbour.position, neighbour.position);
+        }
+        Neighbourhood { neighbours, grid }
+    }
+
+    // Other objects whose centres lie within the distance from the position.
+    fn around(&self, entity: Entity, position: Vec2, distance: f32) -> Vec<Neighbour> {
+        let offset = Vec2::splat(distance);
+        self.grid
+            .query(position - offset, position + offset)
+            .into_iter()
+            .map(|i| self.neighbours[i])
+            .filter(|other| other.entity != entity && other.position.distance(position) <= distance)
+            .collect()
+    }
+
+    pub fn separation(&self, me: &Neighbour) -> Vec2 {
+        self.around(me.entity, me.position, COHESION_RADIUS)
+            .iter()
+            .fold(Vec2::ZERO, |push, other| {
+                let offset = me.position - other.position;
+                let gap = offset.length() - me.radius - other.radius;
+                if gap >= SEPARATION_DISTANCE {
+                    return push;
+                }
+                // Enemies at exactly the same spot have to be pushed in opposite directions,
+                // otherwise they would stay stacked for good.
+                let away = match offset.try_normalize() {
+                    Some(away) => away,
+                    None if me.entity < other.entity => Vec2::X,
+                    None => -Vec2::X,
+                };
+                push + away * (1. - gap.max(0.) / SEPARATION_DISTANCE)
+            })
+            .clamp_length_max(1.)
+    }
+
+    pub fn cohesion(&self, me: &Neighbour) -> Vec2 {
+        let group = self.around(me.entity, me.position, COHESION_RADIUS);
+        if group.is_empty() {
+            return Vec2::ZERO;
+        }
+        let centre = group
+            .iter()
+            .fold(Vec2::ZERO, |sum, other| sum + other.position)
+            / group.len() as f32;
+        (centre - me.position) / COHESION_RADIUS
+    }
+}
+
+// Two feelers, slightly to the left and right of the direction of movement,
+// check for obstacles ahead. The enemy turns to the side with more free space.
+// Feelers are checked against the NavGrid, which is a lot cheaper than casting rays
+// and precise enough, as obstacles are aligned to its cells.
+pub fn avoid_obstacles(nav_grid: &NavGrid, position: Vec2, direction: Vec2) -> Vec2 {
+    let direction = direction.normalize_or_zero();
+    if direction == Vec2::ZERO {
+        return Vec2::ZERO;
+    }
+    let free_space = |angle: f32| {
+        let feeler = rotate(direction, angle);
+        let mut distance = AVOIDANCE_FEELER_STEP;
+        while distance <= AVOIDANCE_LOOKAHEAD {
+            let blocked = nav_grid
+                .cell_of(position + feeler * distance)
+                .is_some_and(|cell| !nav_grid.is_walkable(cell));
+            if blocked {
+                return distance - AVOIDANCE_FEELER_STEP;
+            }
+            distance += AVOIDANCE_FEELER_STEP;
+        }
+        AVOIDANCE_LOOKAHEAD
+    };
+    let left = free_space(AVOIDANCE_FEELER_ANGLE);
+    let right = free_space(-AVOIDANCE_FEELER_ANGLE);
+    let left_normal = Vec2::new(-direction.y, direction.x);
+    left_normal * (left - right) / AVOIDANCE_LOOKAHEAD
+}
+
+// Fraction of the full speed to move with, when heading for a target
+// that should be reached from the given distance.
+pub fn arrival(distance_to_target: f32, stopping_distance: f32) -> f32 {
+    ((distance_to_target - stopping_distance) / ARRIVAL_RADIUS).clamp(0., 1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbour(id: u32, x: f32, y: f32) -> Neighbour {
+        Neighbour {
+            entity: Entity::from_raw(id),
+            position: Vec2::new(x, y),
+            radius: 10.,
+        }
+    }
+
+    #[test]
+    fn coincident_neighbours_are_pushed_apart() {
+        let first = neighbour(0, 5., 5.);
+        let second = neighbour(1, 5., 5.);
+        let neighbourhood = Neighbourhood::new(vec![first, second]);
+        let first_push = neighbourhood.separation(&first);
+        let second_push = neighbourhood.separation(&second);
+        assert!(first_push.length() > 0.);
+        assert_eq!(first_push, -second_push);
+    }
+
+    #[test]
+    fn separation_points_away_from_close_neighbours_only() {
+        let me = neighbour(0, 0., 0.);
+        let close = neighbour(1, 25., 0.);
+        let far = neighbour(2, 0., 60.);
+        let neighbourhood = Neighbourhood::new(vec![me, close, far]);
+        let push = neighbourhood.separation(&me);
+        assert!(push.x < 0.);
+        assert_eq!(push.y, 0.);
+    }
+}
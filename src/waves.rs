@@ -0,0 +1,334 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::boss::{spawn_boss, BossDefinitions};
+use crate::collision_layers::CollisionMatrix;
+use crate::enemy::{spawn_enemy, EnemyMarker, SpawnEnemy};
+use crate::enemy_archetype::EnemyArchetypes;
+use crate::map::MapRendered;
+use crate::parser::MapId;
+use crate::simulation::{run_in_state, GameRng, SimulationAppExt, SIMULATION_TICK};
+use crate::spawn_placement::SpawnPlacement;
+use crate::AppState;
+
+/*
+    Wave director - decides which enemies attack the player in every room (map).
+
+    Waves of every room are read from JSON files in WAVES_PATH. A file without
+    a room is used for rooms that have no file of their own.
+
+    Example of a file:
+        {
+            "room": 1,
+            "waves": [
+                { "delay": 1.0, "budget": 4, "mix": { "grunt": 3.0, "runner": 1.0 } },
+                { "delay": 2.0, "budget": 8, "mix": { "grunt": 1.0, "spitter": 1.0 }, "boss": "warden" }
+            ]
+        }
+
+    Every wave has a budget, which is spent on enemies of the archetypes
+    from the mix (each archetype has its cost). The numbers in the mix are weights:
+    the higher the weight, the more likely the enemy is chosen. The budget grows
+    with every room cleared and every wave of the room (the difficulty curve).
+
+    A wave starts "delay" seconds after the previous one has been defeated
+    (or after entering the room in the case of the first one).
+    A wave is defeated when all its enemies and all other enemies in the room,
+    e.g. summoned by a boss, are dead. When the last wave of the room
+    is defeated, RoomCleared is sent.
+*/
+
+const WAVES_PATH: &str = "data/waves";
+
+// Difficulty curve: how much bigger the budgets get.
+const DIFFICULTY_PER_ROOM: f32 = 0.25;
+const DIFFICULTY_PER_WAVE: f32 = 0.1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct DirectWaves;
+
+pub struct WavePlugin;
+
+impl Plugin for WavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RoomCleared>()
+            .insert_resource(WaveDirector::new())
+            .add_startup_system(load_waves)
+            .add_system_set(SystemSet::on_update(AppState::InGame).with_system(enter_room))
+            .add_system_set(SystemSet::on_exit(AppState::InGame).with_system(reset_waves))
+            .add_simulation_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_in_state(AppState::InGame))
+                    .with_system(direct_waves.label(DirectWaves).after(SpawnEnemy)),
+            );
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Wave {
+    pub delay: f32,
+    pub budget: usize,
+    pub mix: HashMap<String, f32>,
+    // Spawned on top of the enemies bought with the budget.
+    #[serde(default)]
+    pub boss: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct RoomWaves {
+    #[serde(default)]
+    room: Option<MapId>,
+    waves: Vec<Wave>,
+}
+
+/// Waves of every room by its ID.
+pub struct WaveTable {
+    rooms: HashMap<MapId, Vec<Wave>>,
+    default: Vec<Wave>,
+}
+
+impl WaveTable {
+    pub fn waves_of(&self, room: MapId) -> &[Wave] {
+        self.rooms.get(&room).unwrap_or(&self.default)
+    }
+}
+
+/// Sent when the last wave of a room is defeated.
+pub struct RoomCleared {
+    pub room: MapId,
+}
+
+enum WaveState {
+    // No room has been entered yet, or it has been cleared.
+    Inactive,
+    // Waiting before spawning the next wave.
+    Waiting { timer: f32 },
+    // Enemies of the current wave (or summoned by them) are still alive.
+    Fighting,
+}
+
+pub struct WaveDirector {
+    room: Option<MapId>,
+    waves: Vec<Wave>,
+    // Index of the wave that is fought or will be spawned next.
+    wave: usize,
+    state: WaveState,
+    rooms_cleared: u32,
+}
+
+impl WaveDirector {
+    pub fn new() -> WaveDirector {
+        WaveDirector {
+            room: None,
+            waves: vec![],
+            wave: 0,
+            state: WaveState::Inactive,
+            rooms_cleared: 0,
+        }
+    }
+
+    pub fn rooms_cleared(&self) -> u32 {
+        self.rooms_cleared
+    }
+
+    fn start_room(&mut self, room: MapId, waves: Vec<Wave>) {
+        self.room = Some(room);
+        self.wave = 0;
+        self.state = match waves.first() {
+            Some(wave) => WaveState::Waiting { timer: wave.delay },
+            None => WaveState::Inactive,
+        };
+        self.waves = waves;
+    }
+
+    fn difficulty(&self) -> f32 {
+        1. + DIFFICULTY_PER_ROOM * self.rooms_cleared as f32
+            + DIFFICULTY_PER_WAVE * self.wave as f32
+    }
+}
+
+// Chooses archetypes from the mix at random, until nothing fits in the budget.
+fn choose_enemies(
+    mix: &HashMap<String, f32>,
+    budget: usize,
+    archetypes: &EnemyArchetypes,
+    rng: &mut impl Rng,
+) -> Vec<String> {
+    let mut chosen = vec![];
+    let mut budget = budget;
+    loop {
+        let mut affordable: Vec<(&String, f32)> = mix
+            .iter()
+            .filter(|(id, &weight)| {
+                weight > 0.
+                    && archetypes
+                        .get(id)
+                        .is_some_and(|archetype| archetype.cost <= budget)
+            })
+            .map(|(id, &weight)| (id, weight))
+            .collect();
+        // The order of a HashMap differs between runs, the choice should not.
+        affordable.sort_by_key(|(id, _)| *id);
+        let total_weight: f32 = affordable.iter().map(|(_, weight)| weight).sum();
+        if affordable.is_empty() {
+            return chosen;
+        }
+
+        let mut roll = rng.gen_range(0.0..total_weight);
+        let (id, _) = affordable
+            .iter()
+            .find(|(_, weight)| {
+                roll -= weight;
+                roll < 0.
+            })
+            .unwrap_or(&affordable[affordable.len() - 1]);
+        // Costs of 0 would never use up the budget.
+        budget -= archetypes.get(id).unwrap().cost.max(1).min(budget);
+        chosen.push(id.to_string());
+    }
+}
+
+fn read_room_waves(path: &std::path::Path) -> Option<RoomWaves> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn load_waves(mut commands: Commands) {
+    let mut rooms = HashMap::new();
+    let mut default = vec![];
+    let entries = fs::read_dir(WAVES_PATH).expect("Couldn't read the waves directory");
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match read_room_waves(&path) {
+            Some(RoomWaves {
+                room: Some(room),
+                waves,
+            }) => {
+                rooms.insert(room, waves);
+            }
+            Some(RoomWaves { room: None, waves }) => default = waves,
+            None => eprintln!("[load_waves] Incorrect waves file {}.", path.display()),
+        }
+    }
+    commands.insert_resource(WaveTable { rooms, default });
+}
+
+// Rendering a map means entering a room.
+fn enter_room(
+    mut rendered: EventReader<MapRendered>,
+    mut director: ResMut<WaveDirector>,
+    table: Res<WaveTable>,
+) {
+    for MapRendered(room) in rendered.iter() {
+        director.start_room(*room, table.waves_of(*room).to_vec());
+    }
+}
+
+// The next game starts from scratch.
+fn reset_waves(mut director: ResMut<WaveDirector>) {
+    *director = WaveDirector::new();
+}
+
+// Everything needed to bring the enemies of a wave into the room.
+#[derive(SystemParam)]
+struct WaveSpawner<'w, 's> {
+    commands: Commands<'w, 's>,
+    placement: SpawnPlacement<'w, 's>,
+    archetypes: Res<'w, EnemyArchetypes>,
+    bosses: Res<'w, BossDefinitions>,
+    collision_matrix: Res<'w, CollisionMatrix>,
+    rng: ResMut<'w, GameRng>,
+}
+
+impl<'w, 's> WaveSpawner<'w, 's> {
+    // Spawns the enemies (and the boss) of the wave.
+    // Enemies for which there is no place in the room are skipped.
+    fn spawn_wave(&mut self, wave: &Wave, budget: usize) {
+        let mut taken = vec![];
+        for id in choose_enemies(&wave.mix, budget, &self.archetypes, &mut *self.rng) {
+            let archetype = self.archetypes.get(&id).unwrap();
+            let position =
+                match self
+                    .placement
+                    .anywhere(&archetype.hitbox, &mut taken, &mut *self.rng)
+                {
+                    Some(position) => position,
+                    None => {
+                        eprintln!("[direct_waves] There is no place for {}.", id);
+                        continue;
+                    }
+                };
+            spawn_enemy(
+                &mut self.commands,
+                &self.collision_matrix,
+                position.x,
+                position.y,
+                archetype,
+            );
+        }
+        if let Some(boss) = &wave.boss {
+            match self.bosses.get(boss) {
+                Some(definition) => match self.placement.anywhere(
+                    &definition.archetype.hitbox,
+                    &mut taken,
+                    &mut *self.rng,
+                ) {
+                    Some(position) => {
+                        spawn_boss(
+                            &mut self.commands,
+                            &self.collision_matrix,
+                            definition,
+                            position,
+                        );
+                    }
+                    None => eprintln!("[direct_waves] There is no place for the boss {}.", boss),
+                },
+                None => eprintln!("[direct_waves] There is no boss {}.", boss),
+            }
+        }
+    }
+}
+
+fn direct_waves(
+    mut director: ResMut<WaveDirector>,
+    mut cleared_writer: EventWriter<RoomCleared>,
+    enemies: Query<Entity, With<EnemyMarker>>,
+    mut spawner: WaveSpawner,
+) {
+    let director = &mut *director;
+    match &mut director.state {
+        WaveState::Inactive => (),
+        WaveState::Waiting { timer } => {
+            *timer -= SIMULATION_TICK;
+            // The NavGrid of the room may not have been built yet.
+            if *timer > 0. || !spawner.placement.is_ready() {
+                return;
+            }
+            let wave = director.waves[director.wave].clone();
+            let budget = (wave.budget as f32 * director.difficulty()).round() as usize;
+            spawner.spawn_wave(&wave, budget);
+            director.state = WaveState::Fighting;
+        }
+        WaveState::Fighting => {
+            // The enemies spawned in the last tick already exist.
+            if !enemies.is_empty() {
+                return;
+            }
+            director.wave += 1;
+            director.state = match director.waves.get(director.wave) {
+                Some(wave) => WaveState::Waiting { timer: wave.delay },
+                None => {
+                    director.rooms_cleared += 1;
+                    if let Some(room) = director.room {
+                        cleared_writer.send(RoomCleared { room });
+                    }
+                    WaveState::Inactive
+                }
+            };
+        }
+    }
+}